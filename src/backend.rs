@@ -0,0 +1,230 @@
+use crate::mockfs::{self, MockFs};
+use std::ffi::{CStr, CString, OsString};
+use std::io;
+use std::mem;
+use std::os::fd::RawFd;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+
+/// The system calls the resolver makes, as safe methods reporting failures
+/// as `io::Error`s with the errno in them.
+///
+/// [`LibcFs`] makes them for real and [`MockFs`] against its tree, so one
+/// test can run the same scenario through both. Clones act on the same file
+/// system, as every [`SafeFd`](crate::SafeFd) keeps one to close itself with.
+pub trait FileSystem: Clone {
+    /// `mode` is what a file created with `O_CREAT` or `O_TMPFILE` gets,
+    /// before the umask.
+    fn openat(
+        &self,
+        dirfd: RawFd,
+        path: &CStr,
+        flags: c_int,
+        mode: libc::mode_t,
+    ) -> io::Result<RawFd>;
+    /// Fails with `ENOSYS` where there is no `openat2`.
+    fn openat2(&self, dirfd: RawFd, path: &CStr, how: &libc::open_how) -> io::Result<RawFd>;
+    fn readlinkat(&self, dirfd: RawFd, path: &CStr) -> io::Result<PathBuf>;
+    fn close(&self, fd: RawFd) -> io::Result<()>;
+    fn read(&self, fd: RawFd, buf: &mut [u8]) -> io::Result<usize>;
+    fn fstat(&self, fd: RawFd) -> io::Result<libc::stat>;
+    /// Follows symlinks, as `stat` does.
+    fn stat(&self, path: &CStr) -> io::Result<libc::stat>;
+    fn ftruncate(&self, fd: RawFd, length: libc::off_t) -> io::Result<()>;
+    /// The descriptors open, in ascending order.
+    fn open_fds(&self) -> Vec<RawFd>;
+}
+
+/// The real file system, through libc.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LibcFs;
+
+/// The backend a [`Resolver::new`](crate::Resolver::new) uses: [`LibcFs`],
+/// or under `feature = "mock"` the [`MockFs`] installed on the thread that
+/// creates it.
+#[cfg(not(feature = "mock"))]
+pub type DefaultFs = LibcFs;
+#[cfg(feature = "mock")]
+pub type DefaultFs = MockFs;
+
+#[cfg(not(feature = "mock"))]
+pub(crate) fn default_fs() -> DefaultFs {
+    LibcFs
+}
+
+#[cfg(feature = "mock")]
+pub(crate) fn default_fs() -> DefaultFs {
+    MockFs::current()
+}
+
+impl FileSystem for LibcFs {
+    fn openat(
+        &self,
+        dirfd: RawFd,
+        path: &CStr,
+        flags: c_int,
+        mode: libc::mode_t,
+    ) -> io::Result<RawFd> {
+        check(unsafe { libc::openat(dirfd, path.as_ptr(), flags, mode) })
+    }
+
+    fn openat2(&self, dirfd: RawFd, path: &CStr, how: &libc::open_how) -> io::Result<RawFd> {
+        let size = mem::size_of::<libc::open_how>();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dirfd,
+                path.as_ptr(),
+                how as *const _,
+                size,
+            )
+        };
+        check(fd).map(|fd| fd as RawFd)
+    }
+
+    fn readlinkat(&self, dirfd: RawFd, path: &CStr) -> io::Result<PathBuf> {
+        let mut target = vec![0u8; libc::PATH_MAX as usize];
+        let length = unsafe {
+            libc::readlinkat(
+                dirfd,
+                path.as_ptr(),
+                target.as_mut_ptr() as *mut c_char,
+                target.len(),
+            )
+        };
+        target.truncate(check(length)? as usize);
+        Ok(PathBuf::from(OsString::from_vec(target)))
+    }
+
+    fn close(&self, fd: RawFd) -> io::Result<()> {
+        check(unsafe { libc::close(fd) }).map(drop)
+    }
+
+    fn read(&self, fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+        let length = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        check(length).map(|length| length as usize)
+    }
+
+    fn fstat(&self, fd: RawFd) -> io::Result<libc::stat> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        check(unsafe { libc::fstat(fd, &mut st) })?;
+        Ok(st)
+    }
+
+    fn stat(&self, path: &CStr) -> io::Result<libc::stat> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        check(unsafe { libc::stat(path.as_ptr(), &mut st) })?;
+        Ok(st)
+    }
+
+    fn ftruncate(&self, fd: RawFd, length: libc::off_t) -> io::Result<()> {
+        check(unsafe { libc::ftruncate(fd, length) }).map(drop)
+    }
+
+    /// Read from `/proc/self/fd`, so descriptors of other threads show up as
+    /// well.
+    fn open_fds(&self) -> Vec<RawFd> {
+        let fds: Vec<RawFd> = match std::fs::read_dir("/proc/self/fd") {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .collect(),
+            Err(_) => return Vec::new(),
+        };
+        // drop the one the directory stream itself was using
+        let mut fds: Vec<RawFd> = fds
+            .into_iter()
+            .filter(|&fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1)
+            .collect();
+        fds.sort_unstable();
+        fds
+    }
+}
+
+fn check<T: PartialEq + From<i8>>(res: T) -> io::Result<T> {
+    if res == T::from(-1) {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(res)
+}
+
+/// Every call acts on this file system, whichever one is installed on the
+/// calling thread.
+impl FileSystem for MockFs {
+    fn openat(
+        &self,
+        dirfd: RawFd,
+        path: &CStr,
+        flags: c_int,
+        mode: libc::mode_t,
+    ) -> io::Result<RawFd> {
+        self.enter(|| check_mock(unsafe { mockfs::openat(dirfd, path.as_ptr(), flags, mode) }))
+    }
+
+    fn openat2(&self, dirfd: RawFd, path: &CStr, how: &libc::open_how) -> io::Result<RawFd> {
+        let size = mem::size_of::<libc::open_how>();
+        self.enter(|| check_mock(unsafe { mockfs::openat2(dirfd, path.as_ptr(), how, size) }))
+    }
+
+    fn readlinkat(&self, dirfd: RawFd, path: &CStr) -> io::Result<PathBuf> {
+        let mut target = vec![0u8; libc::PATH_MAX as usize];
+        let length = self.enter(|| unsafe {
+            let length = mockfs::readlinkat(
+                dirfd,
+                path.as_ptr(),
+                target.as_mut_ptr() as *mut c_char,
+                target.len(),
+            );
+            check_mock(length)
+        })?;
+        target.truncate(length as usize);
+        Ok(PathBuf::from(OsString::from_vec(target)))
+    }
+
+    fn close(&self, fd: RawFd) -> io::Result<()> {
+        self.enter(|| check_mock(unsafe { mockfs::close(fd) }))
+            .map(drop)
+    }
+
+    fn read(&self, fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+        self.enter(|| {
+            let length = unsafe { mockfs::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+            check_mock(length)
+        })
+        .map(|length| length as usize)
+    }
+
+    fn fstat(&self, fd: RawFd) -> io::Result<libc::stat> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        self.enter(|| check_mock(unsafe { mockfs::fstat(fd, &mut st) }))?;
+        Ok(st)
+    }
+
+    fn stat(&self, path: &CStr) -> io::Result<libc::stat> {
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        self.enter(|| check_mock(unsafe { mockfs::stat(path.as_ptr(), &mut st) }))?;
+        Ok(st)
+    }
+
+    fn ftruncate(&self, fd: RawFd, length: libc::off_t) -> io::Result<()> {
+        self.enter(|| check_mock(unsafe { mockfs::ftruncate(fd, length) }))
+            .map(drop)
+    }
+
+    fn open_fds(&self) -> Vec<RawFd> {
+        self.enter(mockfs::open_fds)
+    }
+}
+
+/// Like [`check`], with the errno the mock set.
+fn check_mock<T: PartialEq + From<i8>>(res: T) -> io::Result<T> {
+    if res == T::from(-1) {
+        return Err(io::Error::from_raw_os_error(mockfs::errno()));
+    }
+    Ok(res)
+}
+
+/// `path` as a C string, or `InvalidInput` if it has a NUL byte.
+pub(crate) fn c_path(path: &str) -> io::Result<CString> {
+    CString::new(path).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
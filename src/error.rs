@@ -0,0 +1,106 @@
+use std::fmt;
+use std::io;
+use std::os::raw::c_int;
+
+/// Why a `safe_open` failed. Every variant carries the absolute path of the
+/// offending component, as far as the walk got, rather than the path the
+/// caller passed in.
+#[derive(Debug)]
+pub enum OpenError {
+    /// A component does not exist.
+    NotFound { path: String },
+    /// A component that has to be walked through is not a directory.
+    NotADirectory { path: String },
+    /// Following `path` would exceed the resolver's budget of symlink hops.
+    SymlinkLoop { path: String, limit: usize },
+    /// The path, including the targets of its symlinks, has more components
+    /// than the resolver is willing to walk.
+    TooManyComponents { path: String, limit: usize },
+    /// A `..` would have left the directory the walk is confined to.
+    EscapesRoot { path: String },
+    /// The policy refused a component. `rule` names the rule that fired when
+    /// the policy can tell.
+    AccessDenied { path: String, rule: Option<String> },
+    /// The path or a symlink target contains a NUL byte or is not UTF-8.
+    InvalidPath { path: String },
+    /// Any other failure of the underlying system call.
+    Os { path: String, errno: c_int },
+}
+
+impl OpenError {
+    pub(crate) fn from_errno(path: String, errno: c_int) -> Self {
+        match errno {
+            libc::ENOENT => OpenError::NotFound { path },
+            libc::ENOTDIR => OpenError::NotADirectory { path },
+            _ => OpenError::Os { path, errno },
+        }
+    }
+
+    pub(crate) fn from_io(path: String, err: io::Error) -> Self {
+        OpenError::from_errno(path, err.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            OpenError::NotFound { path }
+            | OpenError::NotADirectory { path }
+            | OpenError::SymlinkLoop { path, .. }
+            | OpenError::TooManyComponents { path, .. }
+            | OpenError::EscapesRoot { path }
+            | OpenError::AccessDenied { path, .. }
+            | OpenError::InvalidPath { path }
+            | OpenError::Os { path, .. } => path,
+        }
+    }
+
+    /// The closest errno value, for callers that report errors the libc way.
+    pub fn errno(&self) -> c_int {
+        match self {
+            OpenError::NotFound { .. } => libc::ENOENT,
+            OpenError::NotADirectory { .. } => libc::ENOTDIR,
+            OpenError::SymlinkLoop { .. } => libc::ELOOP,
+            OpenError::TooManyComponents { .. } => libc::ENAMETOOLONG,
+            OpenError::EscapesRoot { .. } => libc::EXDEV,
+            OpenError::AccessDenied { .. } => libc::EACCES,
+            OpenError::InvalidPath { .. } => libc::EINVAL,
+            OpenError::Os { errno, .. } => *errno,
+        }
+    }
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenError::NotFound { path } => write!(f, "{}: no such file or directory", path),
+            OpenError::NotADirectory { path } => write!(f, "{}: not a directory", path),
+            OpenError::SymlinkLoop { path, limit } => {
+                write!(f, "{}: more than {} levels of symbolic links", path, limit)
+            }
+            OpenError::TooManyComponents { path, limit } => {
+                write!(f, "{}: more than {} path components", path, limit)
+            }
+            OpenError::EscapesRoot { path } => {
+                write!(f, "{}: escapes the starting directory", path)
+            }
+            OpenError::AccessDenied {
+                path,
+                rule: Some(rule),
+            } => {
+                write!(f, "{}: denied by policy ({})", path, rule)
+            }
+            OpenError::AccessDenied { path, rule: None } => write!(f, "{}: denied by policy", path),
+            OpenError::InvalidPath { path } => write!(f, "{:?}: invalid path", path),
+            OpenError::Os { path, errno } => {
+                write!(f, "{}: {}", path, io::Error::from_raw_os_error(*errno))
+            }
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<OpenError> for io::Error {
+    fn from(err: OpenError) -> Self {
+        io::Error::new(io::Error::from_raw_os_error(err.errno()).kind(), err)
+    }
+}
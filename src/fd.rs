@@ -0,0 +1,178 @@
+use crate::backend::{c_path, default_fs, DefaultFs, FileSystem, LibcFs};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+
+/// An owned file descriptor returned by the resolver, on the file system
+/// `F` it was opened on.
+///
+/// The descriptor is closed when the value is dropped. One opened through
+/// [`LibcFs`] can be turned into a `std::fs::File` or an `OwnedFd`; a
+/// [`MockFs`](crate::mockfs::MockFs) one only exists in the mock fd table,
+/// so those conversions are not provided for it.
+pub struct SafeFd<F: FileSystem = DefaultFs> {
+    fd: RawFd,
+    fs: F,
+}
+
+impl<F: FileSystem> SafeFd<F> {
+    /// Takes ownership of `fd`, which `fs` opened.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open descriptor that is not owned by anything else.
+    pub unsafe fn from_raw(fd: RawFd, fs: F) -> Self {
+        SafeFd { fd, fs }
+    }
+
+    /// Releases ownership of the descriptor without closing it.
+    pub fn into_raw(self) -> RawFd {
+        let mut this = ManuallyDrop::new(self);
+        // the file system is dropped, only the descriptor is kept
+        unsafe { std::ptr::drop_in_place(&mut this.fs) };
+        this.fd
+    }
+
+    pub fn file_id(&self) -> io::Result<FileId> {
+        self.fs.fstat(self.fd).map(|st| FileId::from(&st))
+    }
+
+    /// The file system the descriptor is open on.
+    pub fn fs(&self) -> &F {
+        &self.fs
+    }
+}
+
+/// Which file something is, whatever it is called: the device it is on and
+/// its inode number there. All hard links to a file share it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub dev: libc::dev_t,
+    pub ino: libc::ino_t,
+}
+
+impl FileId {
+    /// The file `fd` refers to, on the default file system.
+    pub fn of(fd: RawFd) -> io::Result<FileId> {
+        default_fs().fstat(fd).map(|st| FileId::from(&st))
+    }
+
+    /// The file `path` leads to on the default file system, following
+    /// symlinks.
+    pub fn of_path(path: &str) -> io::Result<FileId> {
+        FileId::of_path_in(&default_fs(), path)
+    }
+
+    /// Like [`FileId::of_path`], on `fs`.
+    pub fn of_path_in<F: FileSystem>(fs: &F, path: &str) -> io::Result<FileId> {
+        fs.stat(&c_path(path)?).map(|st| FileId::from(&st))
+    }
+}
+
+impl From<&libc::stat> for FileId {
+    fn from(st: &libc::stat) -> Self {
+        FileId {
+            dev: st.st_dev,
+            ino: st.st_ino,
+        }
+    }
+}
+
+impl<F: FileSystem> fmt::Debug for SafeFd<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SafeFd").field("fd", &self.fd).finish()
+    }
+}
+
+impl<F: FileSystem> AsRawFd for SafeFd<F> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl<F: FileSystem> IntoRawFd for SafeFd<F> {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_raw()
+    }
+}
+
+impl FromRawFd for SafeFd<LibcFs> {
+    /// # Safety
+    ///
+    /// `fd` must be an open descriptor that is not owned by anything else.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        SafeFd::from_raw(fd, LibcFs)
+    }
+}
+
+impl<F: FileSystem> Drop for SafeFd<F> {
+    fn drop(&mut self) {
+        let _ = self.fs.close(self.fd);
+    }
+}
+
+/// The descriptors this process has open on the default file system, in
+/// ascending order. With the real backend they are read from
+/// `/proc/self/fd`, so descriptors of other threads show up as well.
+pub fn open_fds() -> Vec<RawFd> {
+    default_fs().open_fds()
+}
+
+impl AsFd for SafeFd<LibcFs> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl From<SafeFd<LibcFs>> for OwnedFd {
+    fn from(fd: SafeFd<LibcFs>) -> Self {
+        unsafe { OwnedFd::from_raw_fd(fd.into_raw()) }
+    }
+}
+
+impl From<SafeFd<LibcFs>> for File {
+    fn from(fd: SafeFd<LibcFs>) -> Self {
+        File::from(OwnedFd::from(fd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::Read;
+
+    fn open_dev_null() -> SafeFd<LibcFs> {
+        let path = CString::new("/dev/null").unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY) };
+        assert_ne!(fd, -1);
+        unsafe { SafeFd::from_raw_fd(fd) }
+    }
+
+    fn is_open(fd: RawFd) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+    }
+
+    #[test]
+    fn test_drop_closes() {
+        let fd = open_dev_null();
+        let raw = fd.as_raw_fd();
+        assert!(is_open(raw));
+        drop(fd);
+        assert!(!is_open(raw));
+    }
+
+    #[test]
+    fn test_into_file() {
+        let fd = open_dev_null();
+        let raw = fd.as_raw_fd();
+        let mut file = File::from(fd);
+        let mut buf = Vec::new();
+        assert_eq!(file.read_to_end(&mut buf).unwrap(), 0);
+        assert_eq!(file.as_raw_fd(), raw);
+        drop(file);
+        assert!(!is_open(raw));
+    }
+}
@@ -0,0 +1,20 @@
+pub mod backend;
+pub mod error;
+pub mod fd;
+pub mod mockfs;
+pub mod policy;
+mod resolve;
+
+pub use backend::{FileSystem, LibcFs};
+pub use error::OpenError;
+pub use fd::SafeFd;
+pub use policy::Policy;
+pub use resolve::{safe_open, safe_openat, DotDot, Resolver, MAX_COMPONENTS, MAX_SYMLINK_HOPS};
+
+const MAX_PATH_SIZE: usize = 4096;
+const DELIM: &str = "/";
+pub const DIRECTORY: &str = "/home/cs_gakusei/work/rust_sandbox/src/";
+pub const CREDENTIALS: &str = "/home/cs_gakusei/work/rust_sandbox/src/credentials";
+pub const NONCREDENTIAL: &str = "/home/cs_gakusei/work/rust_sandbox/src/noncredential";
+pub const NONCREDENTIAL2: &str = "/home/cs_gakusei/work/rust_sandbox/src/noncredential2";
+pub const SYMLINK: &str = "/home/cs_gakusei/work/rust_sandbox/src/symlink";
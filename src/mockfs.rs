@@ -1,25 +1,42 @@
+// The syscall stand-ins keep libc's unsafe raw-pointer signatures so tests
+// can swap them in for the real ones with a `use`. The resolver goes through
+// the safe `FileSystem` implementation in `backend` instead.
+#![allow(clippy::missing_safety_doc)]
+
+pub mod fixture;
+pub mod host;
+pub mod snapshot;
+
+use fixture::{Fixture, FixtureError};
+
 #[cfg(not(loom))]
 use lazy_static::lazy_static as lazy_static_loom;
-use libc::{O_RDONLY, O_RDWR, O_WRONLY};
 #[cfg(loom)]
 use loom::lazy_static as lazy_static_loom;
 #[cfg(loom)]
 use loom::sync::RwLock;
 #[cfg(loom)]
 use loom::thread_local;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::c_void;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::io;
-use std::os::raw::{c_char, c_int};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint};
 use std::path::Path;
-use std::sync::Arc;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 #[cfg(not(loom))]
 use std::sync::RwLock;
+use std::sync::{Arc, Mutex};
 
 type FileDescriptor = c_int;
+type Ino = u64;
 
+/// A file to [`create`]. In the tree a file is an inode, which directory
+/// entries name by number.
 #[derive(Debug, Clone)]
 pub enum FileType {
     Regular(String),                      // Contains file content
@@ -27,72 +44,795 @@ pub enum FileType {
     Symlink(String),                      // Contains the target path
 }
 
+/// What an inode holds.
+#[derive(Debug, Clone)]
+enum Node {
+    Regular(String),
+    Directory(HashMap<String, Ino>),
+    Symlink(String),
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    node: Node,
+    /// The directory entries naming it. Once there are none and no
+    /// descriptor refers to it either, it is freed.
+    nlink: usize,
+    meta: Metadata,
+}
+
+/// What `stat` tells about an inode besides its type, size and links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Metadata {
+    /// The permission bits, without the file type.
+    mode: libc::mode_t,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    atime: libc::time_t,
+    mtime: libc::time_t,
+    ctime: libc::time_t,
+}
+
+/// The file system: every inode by number, starting from the root directory.
+#[derive(Debug, Clone)]
+struct Tree {
+    inodes: HashMap<Ino, Inode>,
+    /// The directory each directory is in, which has the only entry for it.
+    parents: HashMap<Ino, Ino>,
+    next_ino: Ino,
+    /// The time of the last change, in seconds. Every change ticks it, so
+    /// timestamps order changes and are the same in every run.
+    clock: libc::time_t,
+}
+
+const ROOT_INO: Ino = 1;
+/// The device every mock file is on.
+const MOCK_DEV: libc::dev_t = 1;
+/// The time the tree is created at, in seconds since the epoch.
+const MOCK_EPOCH: libc::time_t = 1_700_000_000;
+/// The block size `stat` reports.
+const MOCK_BLKSIZE: libc::blksize_t = 4096;
+/// The umask of the mocked process.
+const MOCK_UMASK: libc::mode_t = 0o022;
+
+/// The default soft `RLIMIT_NOFILE`.
+const DEFAULT_FD_LIMIT: usize = 1024;
+
+/// A directory stream, as `DIR` is to libc: a descriptor and what was
+/// last read from it.
+pub struct Dir {
+    fd: c_int,
+    buf: Vec<u8>,
+    /// The part of `buf` filled by the last `getdents64`, and how much of
+    /// that has been handed out.
+    len: usize,
+    pos: usize,
+    entry: libc::dirent,
+}
+
+/// Who the mocked process runs as, which decides what it may access.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    /// The supplementary groups.
+    pub groups: Vec<libc::gid_t>,
+}
+
+/// What a descriptor refers to. Duplicates share one, as they share an open
+/// file description in the kernel, and with it the offset.
+#[derive(Debug)]
+struct OpenFile {
+    path: String,
+    ino: Ino,
+    flags: c_int,
+    // only ever locked briefly while the tree is, never the other way round
+    offset: Mutex<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct FdEntry {
+    file: Arc<OpenFile>,
+    cloexec: bool,
+}
+
+/// The descriptor table of the mocked process, shared by all its threads.
+#[derive(Debug)]
+struct FdTable {
+    fds: BTreeMap<FileDescriptor, FdEntry>,
+    limit: usize,
+}
+
+/// A mocked process and the file system it sees: the tree, the descriptor
+/// table, the working directory and the settings. Clones share all of it.
+///
+/// The syscall stand-ins act on the one [installed](MockFs::install) on the
+/// calling thread, or on a default one for the whole process. Under loom
+/// the default is new in every model, and shared by its threads.
+#[derive(Clone)]
+pub struct MockFs {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    tree: RwLock<Tree>,
+    // A std mutex even under loom: which numbers descriptors get is not
+    // modelled state, only the tree is. Only ever locked after the tree,
+    // never the other way round.
+    fds: Mutex<FdTable>,
+    // Not modelled either: tests set these before they race.
+    cwd: Mutex<String>,
+    credentials: Mutex<Credentials>,
+    /// Symlinks one lookup may follow before it fails, like the kernel's
+    /// MAXSYMLINKS.
+    max_symlink_hops: AtomicUsize,
+    openat2_supported: AtomicBool,
+    openat2_calls: AtomicUsize,
+}
+
+/// Keeps a [`MockFs`] installed on a thread; dropping it puts back the one
+/// installed before.
+#[must_use = "the file system is uninstalled when this is dropped"]
+pub struct InstalledFs {
+    previous: Option<MockFs>,
+}
+
 lazy_static_loom! {
-    static ref FS_TREE: RwLock<HashMap<String, FileType>> = RwLock::new(HashMap::new());
+    static ref DEFAULT_FS: MockFs = {
+        let fs = MockFs::new();
+        *fs.inner.cwd.lock().unwrap() = "/home/cs_gakusei/work/rust_sandbox".to_string();
+        fs
+    };
 }
 
+// Under loom these are per modelled thread, and loom's macro takes no
+// `const` initializer.
 thread_local! {
-    static NEXT_FD: RefCell<FileDescriptor> = RefCell::new(3);
-    static OPEN_FILES: RefCell<HashMap<FileDescriptor, String>> = RefCell::new(HashMap::new());
-    static CURRENT_DIR: RefCell<String> = RefCell::new("/home/cs_gakusei/work/rust_sandbox".to_string());
+    // per thread, as libc's is
+    #[allow(clippy::missing_const_for_thread_local)]
+    static ERRNO: Cell<c_int> = Cell::new(0);
+    #[allow(clippy::missing_const_for_thread_local)]
+    static INSTALLED_FS: RefCell<Option<MockFs>> = RefCell::new(None);
 }
 
+/// Creates the files of the demo and the race tests, unless they are there
+/// already.
 pub fn initialize_mockfs() {
-    create(
-        "/home/cs_gakusei/work/rust_sandbox/src/noncredential",
-        FileType::Regular("noncredential content".to_string()),
-    )
-    .unwrap();
-    create(
-        "/home/cs_gakusei/work/rust_sandbox/src/credentials",
-        FileType::Regular("credentials content".to_string()),
-    )
-    .unwrap();
-    create(
-        "/home/cs_gakusei/work/rust_sandbox/src/symlink",
-        FileType::Symlink("/home/cs_gakusei/work/rust_sandbox/src/noncredential".to_string()),
-    )
-    .unwrap();
+    let src = "/home/cs_gakusei/work/rust_sandbox/src";
+    let fixture = Fixture::new()
+        .file(&format!("{}/noncredential", src), "noncredential content")
+        .file(&format!("{}/credentials", src), "credentials content")
+        .symlink(
+            &format!("{}/symlink", src),
+            &format!("{}/noncredential", src),
+        );
+    match fixture.apply() {
+        Ok(())
+        | Err(FixtureError::Apply {
+            errno: libc::EEXIST,
+            ..
+        }) => {}
+        Err(err) => panic!("{}", err),
+    }
+}
+
+/// Runs the mocked process as `credentials` from now on. It starts as root,
+/// which may access anything.
+pub fn set_credentials(credentials: Credentials) {
+    *MockFs::current().inner.credentials.lock().unwrap() = credentials;
+}
+
+pub fn credentials() -> Credentials {
+    MockFs::current().inner.credentials.lock().unwrap().clone()
+}
+
+pub fn set_max_symlink_hops(hops: usize) {
+    MockFs::current()
+        .inner
+        .max_symlink_hops
+        .store(hops, Ordering::Relaxed);
+}
+
+/// Simulates a kernel with (`true`) or without (`false`, the default)
+/// `openat2`.
+pub fn set_openat2_supported(supported: bool) {
+    MockFs::current()
+        .inner
+        .openat2_supported
+        .store(supported, Ordering::Relaxed);
+}
+
+/// How many times `openat2` has been called, supported or not.
+pub fn openat2_calls() -> usize {
+    MockFs::current()
+        .inner
+        .openat2_calls
+        .load(Ordering::Relaxed)
+}
+
+/// Sets the most descriptors the process may have open, like raising or
+/// lowering `RLIMIT_NOFILE`. Descriptors already above it stay open.
+pub fn set_fd_limit(limit: usize) {
+    MockFs::current().inner.fds.lock().unwrap().limit = limit;
 }
 
+/// `open(2)`. A file it creates gets mode 0666, masked like [`openat`]'s.
 pub unsafe fn open(path: *const c_char, oflag: c_int) -> c_int {
-    openat(libc::AT_FDCWD, path, oflag)
+    openat(libc::AT_FDCWD, path, oflag, 0o666)
 }
 
-pub unsafe fn openat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int {
+/// `openat(2)`. Symlinks are followed unless `O_NOFOLLOW` is given, in which
+/// case a final symlink fails with ELOOP, or is itself opened with `O_PATH`.
+/// Like any lookup, an open needs search permission on the directories on
+/// the way; `O_PATH` needs nothing more, other opens also read or write
+/// permission on the file. `O_CREAT` (with `O_EXCL`) and `O_TRUNC` change the tree under the same
+/// write lock as the lookup, so creation is atomic, and a file created gets
+/// `mode` masked with a umask of 022; `O_APPEND` is honoured by `write`. The
+/// descriptor refers to the inode, so it keeps the content even once every
+/// name for it is gone.
+pub unsafe fn openat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    flags: c_int,
+    mode: libc::mode_t,
+) -> c_int {
     let path = CStr::from_ptr(pathname).to_str().unwrap_or("");
-    let components: Vec<&str> = path
-        .split('/')
-        .filter(|&c| !c.is_empty() && c != ".")
-        .collect();
-    println!("openat({}): FS_TREE.read()", path);
-    let fs_tree_lock = FS_TREE.read().unwrap();
+    let flags = if flags & libc::O_PATH != 0 {
+        // everything else is ignored for O_PATH
+        flags & (libc::O_PATH | libc::O_CLOEXEC | libc::O_DIRECTORY | libc::O_NOFOLLOW)
+    } else {
+        flags
+    };
 
-    let base_path = if path.starts_with("/") {
-        "".to_string()
-    } else if dirfd == libc::AT_FDCWD {
-        CURRENT_DIR.with(|v| v.borrow().clone()).to_string()
-    } else if let Some(dir_path) = OPEN_FILES.with(|v| v.borrow().clone()).get(&dirfd) {
-        dir_path.clone()
+    // the descriptor is installed under the lock, so that the inode cannot be
+    // freed before it refers to it
+    let opened = if flags & (libc::O_CREAT | libc::O_TRUNC) != 0 {
+        println!("openat({}): FS_TREE.write()", path);
+        let fs = MockFs::current();
+        let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+        lookup_at(&fs_tree_lock, dirfd, path, flags)
+            .and_then(|target| prepare_open(&mut fs_tree_lock, target, flags, mode))
+            .map(|(resolved_path, ino)| allocate_fd(resolved_path, ino, flags))
+    } else {
+        println!("openat({}): FS_TREE.read()", path);
+        let fs = MockFs::current();
+        let fs_tree_lock = fs.inner.tree.read().unwrap();
+        lookup_at(&fs_tree_lock, dirfd, path, flags)
+            .and_then(|target| check_open(&fs_tree_lock, &target, flags))
+            .map(|(resolved_path, ino)| allocate_fd(resolved_path, ino, flags))
+    };
+
+    match opened {
+        Ok(fd) => fd,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+/// `openat2(2)`, or ENOSYS unless enabled with [`set_openat2_supported`].
+///
+/// Of the `RESOLVE_*` flags only `RESOLVE_BENEATH` and `RESOLVE_NO_SYMLINKS`
+/// are enforced; the checks and the lookup happen under one lock, as the
+/// kernel does them in one call. `O_CREAT` and `O_TRUNC` work as in
+/// [`openat`], with `how.mode` for a file created.
+pub unsafe fn openat2(
+    dirfd: c_int,
+    pathname: *const c_char,
+    how: *const libc::open_how,
+    _size: usize,
+) -> c_int {
+    let fs = MockFs::current();
+    fs.inner.openat2_calls.fetch_add(1, Ordering::Relaxed);
+    if !fs.inner.openat2_supported.load(Ordering::Relaxed) {
+        set_errno(libc::ENOSYS);
+        return -1;
+    }
+    let how = &*how;
+    let path = CStr::from_ptr(pathname).to_str().unwrap_or("");
+
+    if how.resolve & libc::RESOLVE_BENEATH != 0 {
+        if path.starts_with('/') {
+            set_errno(libc::EXDEV);
+            return -1;
+        }
+        let mut depth = 0usize;
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." if depth == 0 => {
+                    set_errno(libc::EXDEV);
+                    return -1;
+                }
+                ".." => depth -= 1,
+                _ => depth += 1,
+            }
+        }
+    }
+
+    let flags = how.flags as c_int;
+    let opened = if flags & (libc::O_CREAT | libc::O_TRUNC) != 0 {
+        println!("openat2({}): FS_TREE.write()", path);
+        let fs = MockFs::current();
+        let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+        check_resolve(&fs_tree_lock, dirfd, path, how.resolve)
+            .and_then(|()| lookup_at(&fs_tree_lock, dirfd, path, flags))
+            .and_then(|target| {
+                prepare_open(&mut fs_tree_lock, target, flags, how.mode as libc::mode_t)
+            })
+            .map(|(resolved_path, ino)| allocate_fd(resolved_path, ino, flags))
     } else {
+        println!("openat2({}): FS_TREE.read()", path);
+        let fs = MockFs::current();
+        let fs_tree_lock = fs.inner.tree.read().unwrap();
+        check_resolve(&fs_tree_lock, dirfd, path, how.resolve)
+            .and_then(|()| lookup_at(&fs_tree_lock, dirfd, path, flags))
+            .and_then(|target| check_open(&fs_tree_lock, &target, flags))
+            .map(|(resolved_path, ino)| allocate_fd(resolved_path, ino, flags))
+    };
+
+    match opened {
+        Ok(fd) => fd,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+/// Fails with ELOOP if `resolve` has `RESOLVE_NO_SYMLINKS` and `path`
+/// relative to `dirfd` crosses a symlink, the last component included.
+fn check_resolve(tree: &Tree, dirfd: c_int, path: &str, resolve: u64) -> Result<(), c_int> {
+    if resolve & libc::RESOLVE_NO_SYMLINKS == 0 {
+        return Ok(());
+    }
+    let base_path = base_path(tree, dirfd, path)?;
+    let mut full_components = parse_path(&base_path);
+    for component in path.split('/').filter(|&c| !c.is_empty()) {
+        full_components.push(component);
+        // `/proc/self/fd/N` counts as a (magic) link too
+        if proc_fd(&full_components).is_some()
+            || matches!(
+                traverse_path(tree, &full_components, &mut 0),
+                Ok((ino, _)) if matches!(tree.node(ino), Some(Node::Symlink(_)))
+            )
+        {
+            return Err(libc::ELOOP);
+        }
+    }
+    Ok(())
+}
+
+/// The descriptors the process has open, in ascending order.
+pub fn open_fds() -> Vec<c_int> {
+    let fs = MockFs::current();
+    let table = fs.inner.fds.lock().unwrap();
+    table.fds.keys().copied().collect()
+}
+
+pub unsafe fn close(fd: c_int) -> c_int {
+    let fs = MockFs::current();
+    let removed = fs.inner.fds.lock().unwrap().fds.remove(&fd);
+    match removed {
+        Some(_) => 0,
+        None => {
+            set_errno(libc::EBADF);
+            -1
+        }
+    }
+}
+
+pub unsafe fn dup(oldfd: c_int) -> c_int {
+    fcntl(oldfd, libc::F_DUPFD, 0)
+}
+
+/// Makes `newfd` refer to what `oldfd` does, silently closing whatever
+/// `newfd` referred to before.
+pub unsafe fn dup2(oldfd: c_int, newfd: c_int) -> c_int {
+    let fs = MockFs::current();
+    let mut table = fs.inner.fds.lock().unwrap();
+    let Some(entry) = table.fds.get(&oldfd).cloned() else {
+        set_errno(libc::EBADF);
         return -1;
     };
-    let mut full_components: Vec<_> = base_path.split('/').filter(|&c| !c.is_empty()).collect();
-    full_components.extend(components);
+    if newfd < 0 || newfd as usize >= table.limit {
+        set_errno(libc::EBADF);
+        return -1;
+    }
+    if oldfd != newfd {
+        let entry = FdEntry {
+            cloexec: false,
+            ..entry
+        };
+        table.fds.insert(newfd, entry);
+    }
+    newfd
+}
 
-    if let Some((_, resolved_path)) =
-        traverse_path_recursive(&fs_tree_lock, &full_components, flags)
-    {
-        drop(fs_tree_lock);
-        NEXT_FD.with(|next_fd| {
-            let new_fd = *next_fd.borrow();
-            register_fd_in_proc(resolved_path.as_str(), new_fd);
-            OPEN_FILES.with(|open_files| (*open_files.borrow_mut()).insert(new_fd, resolved_path));
-            *next_fd.borrow_mut() += 1;
-            new_fd
-        })
+/// `fcntl(2)` for `F_DUPFD`, `F_DUPFD_CLOEXEC`, `F_GETFD`, `F_SETFD` and
+/// `F_GETFL`; other commands fail with EINVAL.
+pub unsafe fn fcntl(fd: c_int, cmd: c_int, arg: c_int) -> c_int {
+    let fs = MockFs::current();
+    let mut table = fs.inner.fds.lock().unwrap();
+    let Some(entry) = table.fds.get_mut(&fd) else {
+        set_errno(libc::EBADF);
+        return -1;
+    };
+    match cmd {
+        libc::F_DUPFD | libc::F_DUPFD_CLOEXEC => {
+            let entry = FdEntry {
+                file: entry.file.clone(),
+                cloexec: cmd == libc::F_DUPFD_CLOEXEC,
+            };
+            if arg < 0 || arg as usize >= table.limit {
+                set_errno(libc::EINVAL);
+                return -1;
+            }
+            table.install(arg, entry)
+        }
+        libc::F_GETFD => {
+            if entry.cloexec {
+                libc::FD_CLOEXEC
+            } else {
+                0
+            }
+        }
+        libc::F_SETFD => {
+            entry.cloexec = arg & libc::FD_CLOEXEC != 0;
+            0
+        }
+        libc::F_GETFL => entry.file.flags,
+        _ => {
+            set_errno(libc::EINVAL);
+            -1
+        }
+    }
+}
+
+pub unsafe fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize {
+    read_file(fd, buf, count, None)
+}
+
+pub unsafe fn pread(fd: c_int, buf: *mut c_void, count: usize, offset: libc::off_t) -> isize {
+    if offset < 0 {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    read_file(fd, buf, count, Some(offset as usize))
+}
+
+pub unsafe fn write(fd: c_int, buf: *const c_void, count: usize) -> isize {
+    write_file(fd, buf, count, None)
+}
+
+pub unsafe fn pwrite(fd: c_int, buf: *const c_void, count: usize, offset: libc::off_t) -> isize {
+    if offset < 0 {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    write_file(fd, buf, count, Some(offset as usize))
+}
+
+/// Moves the file offset. Seeking past the end is allowed; a later write
+/// fills the gap.
+pub unsafe fn lseek(fd: c_int, offset: libc::off_t, whence: c_int) -> libc::off_t {
+    let Some(file) = open_file(fd) else {
+        set_errno(libc::EBADF);
+        return -1;
+    };
+    let base = match whence {
+        libc::SEEK_SET => 0,
+        libc::SEEK_CUR => *file.offset.lock().unwrap() as libc::off_t,
+        libc::SEEK_END => {
+            println!("lseek({}): FS_TREE.read()", fd);
+            let fs = MockFs::current();
+            let fs_tree_lock = fs.inner.tree.read().unwrap();
+            match fs_tree_lock.node(file.ino) {
+                Some(Node::Regular(content)) => content.len() as libc::off_t,
+                Some(_) => 0,
+                None => {
+                    set_errno(libc::ENOENT);
+                    return -1;
+                }
+            }
+        }
+        _ => {
+            set_errno(libc::EINVAL);
+            return -1;
+        }
+    };
+    match base.checked_add(offset) {
+        Some(position) if position >= 0 => {
+            *file.offset.lock().unwrap() = position as usize;
+            position
+        }
+        _ => {
+            set_errno(libc::EINVAL);
+            -1
+        }
+    }
+}
+
+pub unsafe fn ftruncate(fd: c_int, length: libc::off_t) -> c_int {
+    let file = match open_file_for(fd, libc::O_WRONLY) {
+        Ok(file) => file,
+        Err(errno) => {
+            // open, but not for writing
+            let readable = open_file(fd).is_some_and(|file| file.flags & libc::O_PATH == 0);
+            set_errno(if readable { libc::EINVAL } else { errno });
+            return -1;
+        }
+    };
+    if length < 0 {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    println!("ftruncate({}): FS_TREE.write()", fd);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+    let content = match fs_tree_lock.content_mut(file.ino) {
+        Ok(content) => content,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+    let length = length as usize;
+    if length <= content.len() {
+        if !content.is_char_boundary(length) {
+            set_errno(libc::EINVAL);
+            return -1;
+        }
+        content.truncate(length);
     } else {
-        -1
+        let _ = write_at(content, length, &[]);
+    }
+    fs_tree_lock.modified(file.ino);
+    0
+}
+
+/// `fstat(2)`.
+pub unsafe fn fstat(fd: c_int, buf: *mut libc::stat) -> c_int {
+    let Some(file) = open_file(fd) else {
+        set_errno(libc::EBADF);
+        return -1;
+    };
+    println!("fstat({}): FS_TREE.read()", fd);
+    let fs = MockFs::current();
+    let fs_tree_lock = fs.inner.tree.read().unwrap();
+    fill_stat(&fs_tree_lock, file.ino, &mut *buf);
+    0
+}
+
+/// `stat(2)`: like [`fstat`] on what `pathname` leads to, symlinks followed.
+pub unsafe fn stat(pathname: *const c_char, buf: *mut libc::stat) -> c_int {
+    fstatat(libc::AT_FDCWD, pathname, buf, 0)
+}
+
+/// `lstat(2)`: like [`stat`], but a final symlink is described itself.
+pub unsafe fn lstat(pathname: *const c_char, buf: *mut libc::stat) -> c_int {
+    fstatat(libc::AT_FDCWD, pathname, buf, libc::AT_SYMLINK_NOFOLLOW)
+}
+
+/// `fstatat(2)` with `AT_SYMLINK_NOFOLLOW` and `AT_EMPTY_PATH`.
+pub unsafe fn fstatat(
+    dirfd: c_int,
+    pathname: *const c_char,
+    buf: *mut libc::stat,
+    flags: c_int,
+) -> c_int {
+    let path = CStr::from_ptr(pathname).to_str().unwrap_or("");
+    if flags & !(libc::AT_SYMLINK_NOFOLLOW | libc::AT_EMPTY_PATH) != 0 {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    if path.is_empty() {
+        if flags & libc::AT_EMPTY_PATH == 0 {
+            set_errno(libc::ENOENT);
+            return -1;
+        } else if dirfd != libc::AT_FDCWD {
+            return fstat(dirfd, buf);
+        }
+    }
+    println!("fstatat({}): FS_TREE.read()", path);
+    let fs = MockFs::current();
+    let fs_tree_lock = fs.inner.tree.read().unwrap();
+    // the current directory, for an empty path
+    let path = if path.is_empty() { "." } else { path };
+    let follow = flags & libc::AT_SYMLINK_NOFOLLOW == 0;
+    match lookup_ino(&fs_tree_lock, dirfd, path, follow) {
+        Ok(ino) => {
+            fill_stat(&fs_tree_lock, ino, &mut *buf);
+            0
+        }
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+pub unsafe fn chmod(pathname: *const c_char, mode: libc::mode_t) -> c_int {
+    change_path(pathname, true, |tree, ino| tree.set_mode(ino, mode))
+}
+
+pub unsafe fn fchmod(fd: c_int, mode: libc::mode_t) -> c_int {
+    change_fd(fd, |tree, ino| tree.set_mode(ino, mode))
+}
+
+/// `chown(2)`. An id of `-1` leaves that one alone.
+pub unsafe fn chown(pathname: *const c_char, owner: libc::uid_t, group: libc::gid_t) -> c_int {
+    change_path(pathname, true, |tree, ino| {
+        tree.set_owner(ino, owner, group)
+    })
+}
+
+/// `lchown(2)`: like [`chown`], but a final symlink is changed itself.
+pub unsafe fn lchown(pathname: *const c_char, owner: libc::uid_t, group: libc::gid_t) -> c_int {
+    change_path(pathname, false, |tree, ino| {
+        tree.set_owner(ino, owner, group)
+    })
+}
+
+pub unsafe fn fchown(fd: c_int, owner: libc::uid_t, group: libc::gid_t) -> c_int {
+    change_fd(fd, |tree, ino| tree.set_owner(ino, owner, group))
+}
+
+/// The size of a `linux_dirent64` before its name.
+const DIRENT64_HEADER: usize = 19;
+
+/// `getdents64(2)`: fills `dirp` with as many `linux_dirent64` records of
+/// directory `fd` as fit, from its offset on, and returns the bytes used, 0
+/// at the end. The offset counts entries, so one added or removed between
+/// calls may be missed or seen twice, as POSIX allows.
+pub unsafe fn getdents64(fd: c_int, dirp: *mut c_void, count: usize) -> isize {
+    let file = match open_file_for(fd, libc::O_RDONLY) {
+        Ok(file) => file,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+    println!("getdents64({}): FS_TREE.read()", fd);
+    let fs = MockFs::current();
+    let fs_tree_lock = fs.inner.tree.read().unwrap();
+    let listing = match listing(&fs_tree_lock, file.ino) {
+        Ok(listing) => listing,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+
+    let out = slice::from_raw_parts_mut(dirp as *mut u8, count);
+    let mut offset = file.offset.lock().unwrap();
+    let mut used = 0;
+    for (index, (name, ino)) in listing.iter().enumerate().skip(*offset) {
+        let reclen = (DIRENT64_HEADER + name.len() + 1).next_multiple_of(8);
+        if used + reclen > count {
+            if used == 0 {
+                set_errno(libc::EINVAL); // not even one fits
+                return -1;
+            }
+            break;
+        }
+        let record = &mut out[used..used + reclen];
+        record.fill(0);
+        record[0..8].copy_from_slice(&ino.to_ne_bytes());
+        record[8..16].copy_from_slice(&(index as i64 + 1).to_ne_bytes());
+        record[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+        record[18] = dirent_type(&fs_tree_lock, *ino);
+        record[DIRENT64_HEADER..DIRENT64_HEADER + name.len()].copy_from_slice(name.as_bytes());
+        used += reclen;
+        *offset = index + 1;
+    }
+    used as isize
+}
+
+/// `fdopendir(3)`: a stream over directory `fd`, which it then owns.
+pub unsafe fn fdopendir(fd: c_int) -> *mut Dir {
+    let mut st: libc::stat = mem::zeroed();
+    if fstat(fd, &mut st) == -1 {
+        return std::ptr::null_mut();
+    }
+    if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+        set_errno(libc::ENOTDIR);
+        return std::ptr::null_mut();
+    }
+    Box::into_raw(Box::new(Dir {
+        fd,
+        buf: vec![0; 4096],
+        len: 0,
+        pos: 0,
+        entry: mem::zeroed(),
+    }))
+}
+
+pub unsafe fn opendir(name: *const c_char) -> *mut Dir {
+    let fd = open(name, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC);
+    if fd == -1 {
+        return std::ptr::null_mut();
+    }
+    fdopendir(fd)
+}
+
+/// `readdir(3)`: the next entry, or null at the end or on an error, which
+/// only then sets errno. The entry is overwritten by the next call.
+pub unsafe fn readdir(dirp: *mut Dir) -> *mut libc::dirent {
+    let dir = &mut *dirp;
+    if dir.pos >= dir.len {
+        let read = getdents64(dir.fd, dir.buf.as_mut_ptr().cast(), dir.buf.len());
+        if read <= 0 {
+            return std::ptr::null_mut();
+        }
+        (dir.len, dir.pos) = (read as usize, 0);
+    }
+    let record = &dir.buf[dir.pos..dir.len];
+    let reclen = u16::from_ne_bytes([record[16], record[17]]) as usize;
+    let name = &record[DIRENT64_HEADER..reclen];
+    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+    let entry = &mut dir.entry;
+    entry.d_ino = u64::from_ne_bytes(record[0..8].try_into().unwrap());
+    entry.d_off = i64::from_ne_bytes(record[8..16].try_into().unwrap());
+    entry.d_reclen = reclen as u16;
+    entry.d_type = record[18];
+    entry.d_name = [0; 256];
+    for (dst, &src) in entry.d_name.iter_mut().zip(name) {
+        *dst = src as c_char;
+    }
+    dir.pos += reclen;
+    entry
+}
+
+pub unsafe fn dirfd(dirp: *mut Dir) -> c_int {
+    (*dirp).fd
+}
+
+/// `closedir(3)`: frees the stream and closes its descriptor.
+pub unsafe fn closedir(dirp: *mut Dir) -> c_int {
+    let dir = Box::from_raw(dirp);
+    close(dir.fd)
+}
+
+/// `chdir(2)`. The working directory is kept as a path, so it does not
+/// follow the directory if that is renamed.
+pub unsafe fn chdir(path: *const c_char) -> c_int {
+    let path = CStr::from_ptr(path).to_str().unwrap_or("");
+    println!("chdir({}): FS_TREE.read()", path);
+    let fs = MockFs::current();
+    let fs_tree_lock = fs.inner.tree.read().unwrap();
+    let dir = lookup_ino(&fs_tree_lock, libc::AT_FDCWD, path, true)
+        .and_then(|ino| change_dir(&fs_tree_lock, ino));
+    match dir {
+        Ok(dir) => {
+            *fs.inner.cwd.lock().unwrap() = dir;
+            0
+        }
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+pub unsafe fn fchdir(fd: c_int) -> c_int {
+    let Some(file) = open_file(fd) else {
+        set_errno(libc::EBADF);
+        return -1;
+    };
+    println!("fchdir({}): FS_TREE.read()", fd);
+    let fs = MockFs::current();
+    let fs_tree_lock = fs.inner.tree.read().unwrap();
+    match change_dir(&fs_tree_lock, file.ino) {
+        Ok(dir) => {
+            *fs.inner.cwd.lock().unwrap() = dir;
+            0
+        }
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
     }
 }
 
@@ -105,38 +845,43 @@ pub unsafe fn readlinkat(
     let path = CStr::from_ptr(pathname).to_str().unwrap_or("");
     let components: Vec<&str> = path.split('/').filter(|&c| !c.is_empty()).collect();
     println!("readlinkat({}): FS_TREE.read()", path);
-    let fs_tree_lock = FS_TREE.read().unwrap();
+    let fs = MockFs::current();
+    let fs_tree_lock = fs.inner.tree.read().unwrap();
 
     // Determine the starting point in the filesystem based on dirfd
-    let base_path = if path.starts_with("/") {
-        "".to_string()
-    } else if dirfd == libc::AT_FDCWD {
-        CURRENT_DIR.with(|v| v.borrow().clone()).to_string()
-    } else if let Some(dir_path) = OPEN_FILES.with(|v| v.borrow().clone()).get(&dirfd) {
-        dir_path.clone()
-    } else {
-        return -1;
+    let base_path = match base_path(&fs_tree_lock, dirfd, path) {
+        Ok(base_path) => base_path,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
     };
     let mut full_components: Vec<_> = base_path.split('/').filter(|&c| !c.is_empty()).collect();
     full_components.extend(components);
 
+    let file_type = match proc_fd(&full_components) {
+        Some((fd, [])) => fd_path(&fs_tree_lock, fd).map(Node::Symlink),
+        _ => traverse_path(&fs_tree_lock, &full_components, &mut 0)
+            .map(|(ino, _)| fs_tree_lock.inodes[&ino].node.clone()),
+    };
+    drop(fs_tree_lock);
     // Resolve the symlink path within the filesystem tree starting from fs_tree
-    if let Some((file_type, _)) = traverse_path(&fs_tree_lock, &full_components) {
-        drop(fs_tree_lock);
-        let target_path = if let FileType::Symlink(dst_path) = file_type {
-            dst_path
-        } else {
-            path.to_string() // EINVAL in readlink(2) but returns the original path for simplicity
-        };
-        let bytes_to_copy = target_path.as_bytes().len().min(bufsz);
-        for (i, byte) in target_path.as_bytes()[..bytes_to_copy].iter().enumerate() {
-            *buf.add(i) = *byte as c_char;
+    let target_path = match file_type {
+        Ok(Node::Symlink(dst_path)) => dst_path,
+        Ok(_) => {
+            set_errno(libc::EINVAL); // not a symlink
+            return -1;
         }
-        bytes_to_copy as isize
-    } else {
-        drop(fs_tree_lock);
-        -1 // Path does not exist
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+    let bytes_to_copy = target_path.len().min(bufsz);
+    for (i, byte) in target_path.as_bytes()[..bytes_to_copy].iter().enumerate() {
+        *buf.add(i) = *byte as c_char;
     }
+    bytes_to_copy as isize
 }
 
 pub fn read_link<P: AsRef<Path>>(path: P) -> io::Result<std::path::PathBuf> {
@@ -162,7 +907,7 @@ pub fn read_link<P: AsRef<Path>>(path: P) -> io::Result<std::path::PathBuf> {
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8"))?;
         Ok(Path::new(str_slice).to_path_buf())
     } else {
-        Err(io::Error::last_os_error())
+        Err(io::Error::from_raw_os_error(errno()))
     }
 }
 
@@ -177,134 +922,1282 @@ pub fn create(path: &str, file_type: FileType) -> Result<(), &'static str> {
     }
 
     println!("create({}): FS_TREE.write()", path);
-    let mut fs_tree_guard = FS_TREE.write().unwrap();
+    let fs = MockFs::current();
+    let mut fs_tree_guard = fs.inner.tree.write().unwrap();
 
-    // Initialize `sub_tree` as a mutable reference to `FS_TREE`.
-    let mut sub_tree = &mut *fs_tree_guard;
-
-    for component in components.iter().take(components.len() - 1) {
-        // This will create a new directory if it doesn't exist
-        sub_tree = sub_tree
-            .entry(component.to_string())
-            .or_insert_with(|| FileType::Directory(HashMap::new()))
-            .as_directory_mut()?; // Convert to &mut HashMap or return an error if not a directory
-    }
+    let parents = &components[..components.len() - 1];
+    let Ok(dir) = fs_tree_guard.make_dirs(parents, &credentials()) else {
+        return Err("Not a directory");
+    };
 
     // Insert the file or symlink at the appropriate place in the tree
     let name = components.last().unwrap();
-    if sub_tree.contains_key(*name) {
+    if fs_tree_guard.entry(dir, name).is_some() {
         Err("File already exists")
     } else {
-        sub_tree.insert(name.to_string(), file_type);
+        fs_tree_guard.add(dir, name, file_type);
         Ok(())
     }
 }
 
-trait AsDirectoryMut {
-    fn as_directory_mut(&mut self) -> Result<&mut HashMap<String, FileType>, &'static str>;
-}
-
-impl AsDirectoryMut for FileType {
-    fn as_directory_mut(&mut self) -> Result<&mut HashMap<String, FileType>, &'static str> {
-        match self {
-            FileType::Directory(ref mut map) => Ok(map),
-            _ => Err("Not a directory"),
-        }
-    }
-}
-
+/// `remove(3)`: [`unlink`] for a file, [`rmdir`] for a directory, decided
+/// under the same lock as the removal.
 pub unsafe fn remove(filename: *const c_char) -> c_int {
     let path_str = CStr::from_ptr(filename).to_str().unwrap();
-    let path_components = parse_path(path_str);
     println!("remove({}): FS_TREE.write()", path_str);
-    let mut fs_tree_lock = FS_TREE.write().unwrap();
-
-    if path_components.is_empty() {
-        return -1; // Invalid path
-    }
-
-    let parent_path = if path_components.len() == 1 {
-        vec![] // If the path has only one component, then its parent is the root.
-    } else {
-        path_components[..path_components.len() - 1].to_vec()
-    };
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
 
-    let file_name = path_components.last().unwrap();
-
-    if let Some(FileType::Directory(ref mut parent_dir)) =
-        traverse_path_mut(&mut fs_tree_lock, &parent_path)
-    {
-        match parent_dir.get(*file_name) {
-            Some(FileType::Directory(contents)) if contents.is_empty() => {
-                // Only allow removal of empty directories
-                parent_dir.remove(*file_name);
-                0 // Successfully removed
-            }
-            Some(FileType::Regular(_)) | Some(FileType::Symlink(_)) => {
-                // Remove file or symlink
-                parent_dir.remove(*file_name);
-                0 // Successfully removed
-            }
-            _ => -1, // Directory not empty or file not found
+    let removed = lookup_parent(&fs_tree_lock, libc::AT_FDCWD, path_str).and_then(|(dir, name)| {
+        let ino = fs_tree_lock.entry(dir, name).ok_or(libc::ENOENT)?;
+        let flags = if fs_tree_lock.entries(ino).is_some() {
+            libc::AT_REMOVEDIR
+        } else {
+            0
+        };
+        unlink_entry(&mut fs_tree_lock, libc::AT_FDCWD, path_str, flags)
+    });
+    match removed {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
         }
-    } else {
-        -1 // Parent directory not found
     }
 }
 
+/// `link(2)`: makes `dst` another name for the inode `src` names. A symlink
+/// `src` is linked itself rather than followed, as on Linux. Like Linux with
+/// `fs.protected_hardlinks`, only the owner, or someone who may read and
+/// write it, may link a file.
 pub unsafe fn link(src: *const c_char, dst: *const c_char) -> c_int {
     let src_str = CStr::from_ptr(src).to_str().unwrap();
     let dst_str = CStr::from_ptr(dst).to_str().unwrap();
 
-    println!("link({}, {}): FS_TREE.read()", src_str, dst_str);
-    let fs_tree_lock = FS_TREE.read().unwrap();
-    if let Some(_) = traverse_path(&fs_tree_lock, &parse_path(src_str)) {
-        drop(fs_tree_lock);
-        match create(dst_str, FileType::Symlink(src_str.to_string())) {
-            Ok(_) => 0,   // Success
-            Err(_) => -1, // Failed to create symlink
+    println!("link({}, {}): FS_TREE.write()", src_str, dst_str);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+    let linked = traverse_path(&fs_tree_lock, &parse_path(src_str), &mut 0).and_then(|(ino, _)| {
+        if fs_tree_lock.entries(ino).is_some() {
+            return Err(libc::EPERM); // no hard links to directories
+        }
+        let (dir, name) = lookup_parent(&fs_tree_lock, libc::AT_FDCWD, dst_str)?;
+        if fs_tree_lock.entry(dir, name).is_some() {
+            return Err(libc::EEXIST);
+        }
+        let creds = credentials();
+        fs_tree_lock.check_writable_dir(dir, &creds)?;
+        let owner = fs_tree_lock.inodes[&ino].meta.uid;
+        let safe = matches!(fs_tree_lock.node(ino), Some(Node::Regular(_)))
+            && fs_tree_lock.permits(ino, libc::R_OK | libc::W_OK, &creds);
+        if creds.uid != 0 && creds.uid != owner && !safe {
+            return Err(libc::EPERM);
+        }
+        fs_tree_lock.link(dir, name, ino);
+        Ok(())
+    });
+    match linked {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
         }
-    } else {
-        -1 // Source path does not exist
     }
 }
 
-// pub unsafe fn unlink(path: *const c_char) -> c_int {
-//     let path_str = CStr::from_ptr(path).to_str().unwrap();
-//     let path_components = parse_path(path_str);
-//     let mut fs_tree_lock = FS_TREE.write().unwrap();
+pub unsafe fn unlink(path: *const c_char) -> c_int {
+    unlinkat(libc::AT_FDCWD, path, 0)
+}
 
-//     let parent_path = path_components[..path_components.len() - 1].to_vec();
-//     let file_name = path_components.last().unwrap();
+pub unsafe fn rmdir(path: *const c_char) -> c_int {
+    unlinkat(libc::AT_FDCWD, path, libc::AT_REMOVEDIR)
+}
 
-//     if let Some(FileType::Directory(ref mut parent_dir)) =
-//         traverse_path_mut(&mut fs_tree_lock, &parent_path)
-//     {
-//         if parent_dir.remove(*file_name).is_some() {
-//             0 // Successfully removed
-//         } else {
-//             -1 // File not found
-//         }
-//     } else {
-//         -1 // Parent directory not found
-//     }
-// }
+/// `unlinkat(2)`: removes a name of a file, or with `AT_REMOVEDIR` an empty
+/// directory. The file goes with its last name, unless it is still open.
+pub unsafe fn unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int {
+    let path = CStr::from_ptr(pathname).to_str().unwrap_or("");
+    println!("unlinkat({}): FS_TREE.write()", path);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+    match unlink_entry(&mut fs_tree_lock, dirfd, path, flags) {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
 
-fn register_fd_in_proc(path: &str, fd: c_int) {
-    let proc_entry = format!("/proc/self/fd/{}", fd);
-    create(&proc_entry, FileType::Symlink(path.to_string()));
+pub unsafe fn mkdir(path: *const c_char, mode: libc::mode_t) -> c_int {
+    mkdirat(libc::AT_FDCWD, path, mode)
 }
 
-fn convert_relative_to_absolute_path(relative_path: &str) -> String {
-    if relative_path.starts_with("/") {
-        // Already an absolute path, return as is.
-        relative_path.to_string()
+/// `mkdirat(2)`. The mode is masked with a umask of 022.
+pub unsafe fn mkdirat(dirfd: c_int, pathname: *const c_char, mode: libc::mode_t) -> c_int {
+    let path = CStr::from_ptr(pathname).to_str().unwrap_or("");
+    println!("mkdirat({}): FS_TREE.write()", path);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+    match add_entry(
+        &mut fs_tree_lock,
+        dirfd,
+        path,
+        FileType::Directory(HashMap::new()),
+    ) {
+        Ok(ino) => {
+            let meta = &mut fs_tree_lock.inodes.get_mut(&ino).unwrap().meta;
+            meta.mode = mode & 0o7777 & !MOCK_UMASK;
+            0
+        }
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+pub unsafe fn symlink(target: *const c_char, linkpath: *const c_char) -> c_int {
+    symlinkat(target, libc::AT_FDCWD, linkpath)
+}
+
+/// `symlinkat(2)`. The target is stored as given, not looked up.
+pub unsafe fn symlinkat(target: *const c_char, newdirfd: c_int, linkpath: *const c_char) -> c_int {
+    let target = CStr::from_ptr(target).to_str().unwrap_or("");
+    let path = CStr::from_ptr(linkpath).to_str().unwrap_or("");
+    println!("symlinkat({}, {}): FS_TREE.write()", target, path);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+    let added = if target.is_empty() {
+        Err(libc::ENOENT)
+    } else {
+        add_entry(
+            &mut fs_tree_lock,
+            newdirfd,
+            path,
+            FileType::Symlink(target.to_string()),
+        )
+    };
+    match added {
+        Ok(_) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+pub unsafe fn rename(oldpath: *const c_char, newpath: *const c_char) -> c_int {
+    renameat2(libc::AT_FDCWD, oldpath, libc::AT_FDCWD, newpath, 0)
+}
+
+pub unsafe fn renameat(
+    olddirfd: c_int,
+    oldpath: *const c_char,
+    newdirfd: c_int,
+    newpath: *const c_char,
+) -> c_int {
+    renameat2(olddirfd, oldpath, newdirfd, newpath, 0)
+}
+
+/// `renameat2(2)` with `RENAME_NOREPLACE` and `RENAME_EXCHANGE`. Both names
+/// are looked up and changed under one write lock, so no lookup ever sees
+/// neither or both, and a directory's descriptors follow it to its new name.
+pub unsafe fn renameat2(
+    olddirfd: c_int,
+    oldpath: *const c_char,
+    newdirfd: c_int,
+    newpath: *const c_char,
+    flags: c_uint,
+) -> c_int {
+    let old_str = CStr::from_ptr(oldpath).to_str().unwrap_or("");
+    let new_str = CStr::from_ptr(newpath).to_str().unwrap_or("");
+    println!("renameat2({}, {}): FS_TREE.write()", old_str, new_str);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+
+    match rename_entry(
+        &mut fs_tree_lock,
+        (olddirfd, old_str),
+        (newdirfd, new_str),
+        flags,
+    ) {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+impl MockFs {
+    /// A file system with only the root directory, which is also the
+    /// working directory, and a process with only the standard streams open,
+    /// running as root.
+    pub fn new() -> Self {
+        MockFs {
+            inner: Arc::new(Inner {
+                tree: RwLock::new(Tree::new()),
+                fds: Mutex::new(FdTable::new()),
+                cwd: Mutex::new("/".to_string()),
+                credentials: Mutex::new(Credentials::root()),
+                max_symlink_hops: AtomicUsize::new(crate::MAX_SYMLINK_HOPS),
+                openat2_supported: AtomicBool::new(false),
+                openat2_calls: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// The file system the calling thread acts on.
+    pub fn current() -> MockFs {
+        INSTALLED_FS
+            .with(|installed| installed.borrow().clone())
+            .unwrap_or_else(|| DEFAULT_FS.clone())
+    }
+
+    /// Makes the calling thread act on this file system until the returned
+    /// guard is dropped. Other threads, including ones it spawns, are not
+    /// affected; they install a clone themselves.
+    pub fn install(&self) -> InstalledFs {
+        let previous = INSTALLED_FS.with(|installed| installed.replace(Some(self.clone())));
+        InstalledFs { previous }
+    }
+
+    /// Runs `f` with this file system installed on the calling thread.
+    pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _installed = self.install();
+        f()
+    }
+}
+
+impl Default for MockFs {
+    fn default() -> Self {
+        MockFs::new()
+    }
+}
+
+impl Drop for InstalledFs {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        INSTALLED_FS.with(|installed| *installed.borrow_mut() = previous);
+    }
+}
+
+impl Credentials {
+    pub fn root() -> Self {
+        Credentials::user(0, 0)
+    }
+
+    /// A user with no supplementary groups.
+    pub fn user(uid: libc::uid_t, gid: libc::gid_t) -> Self {
+        Credentials {
+            uid,
+            gid,
+            groups: Vec::new(),
+        }
+    }
+
+    fn in_group(&self, gid: libc::gid_t) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+impl Metadata {
+    /// The metadata of a file `owner` created at `time`.
+    fn new(mode: libc::mode_t, owner: &Credentials, time: libc::time_t) -> Self {
+        Metadata {
+            mode,
+            uid: owner.uid,
+            gid: owner.gid,
+            atime: time,
+            mtime: time,
+            ctime: time,
+        }
+    }
+}
+
+impl FdTable {
+    /// A table with the standard streams open, so the first file gets 3.
+    fn new() -> Self {
+        // not in the tree, so reading them fails
+        let stdio = Arc::new(OpenFile {
+            path: "/dev/null".to_string(),
+            ino: 0,
+            flags: libc::O_RDWR,
+            offset: Mutex::new(0),
+        });
+        let fds = (0..3)
+            .map(|fd| {
+                let entry = FdEntry {
+                    file: stdio.clone(),
+                    cloexec: false,
+                };
+                (fd, entry)
+            })
+            .collect();
+        FdTable {
+            fds,
+            limit: DEFAULT_FD_LIMIT,
+        }
+    }
+
+    /// Installs `entry` at the lowest free descriptor not below `min`, as
+    /// `open` and `dup` do, or fails with EMFILE.
+    fn install(&mut self, min: FileDescriptor, entry: FdEntry) -> FileDescriptor {
+        let mut fd = min;
+        for &used in self.fds.range(min..).map(|(fd, _)| fd) {
+            if used != fd {
+                break;
+            }
+            fd += 1;
+        }
+        if fd as usize >= self.limit {
+            set_errno(libc::EMFILE);
+            return -1;
+        }
+        self.fds.insert(fd, entry);
+        fd
+    }
+}
+
+impl Tree {
+    /// A tree holding only the root directory.
+    fn new() -> Self {
+        // the root has no entry naming it, but must never be freed
+        let root = Inode {
+            node: Node::Directory(HashMap::new()),
+            nlink: 1,
+            meta: Metadata::new(0o755, &Credentials::root(), MOCK_EPOCH),
+        };
+        Tree {
+            inodes: HashMap::from([(ROOT_INO, root)]),
+            parents: HashMap::new(),
+            next_ino: ROOT_INO + 1,
+            clock: MOCK_EPOCH,
+        }
+    }
+
+    fn node(&self, ino: Ino) -> Option<&Node> {
+        self.inodes.get(&ino).map(|inode| &inode.node)
+    }
+
+    /// The entries of `ino`, if it is a directory.
+    fn entries(&self, ino: Ino) -> Option<&HashMap<String, Ino>> {
+        match self.node(ino) {
+            Some(Node::Directory(entries)) => Some(entries),
+            _ => None,
+        }
+    }
+
+    /// The inode `name` in directory `dir` refers to.
+    fn entry(&self, dir: Ino, name: &str) -> Option<Ino> {
+        self.entries(dir)?.get(name).copied()
+    }
+
+    /// Where directory `ino` is now, or `None` once it has been removed.
+    fn dir_path(&self, ino: Ino) -> Option<String> {
+        if ino == ROOT_INO {
+            return Some("/".to_string());
+        }
+        let parent = *self.parents.get(&ino)?;
+        let (name, _) = self
+            .entries(parent)?
+            .iter()
+            .find(|(_, &entry)| entry == ino)?;
+        Some(join_path(&self.dir_path(parent)?, name))
+    }
+
+    /// Whether `dir` is directory `ancestor` or somewhere beneath it.
+    fn is_within(&self, dir: Ino, ancestor: Ino) -> bool {
+        let mut current = dir;
+        loop {
+            if current == ancestor {
+                return true;
+            }
+            match self.parents.get(&current) {
+                Some(&parent) => current = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Whether `creds` may access `ino` as `want` asks, a mask of `R_OK`,
+    /// `W_OK` and `X_OK`. Root may do anything but execute a file nobody
+    /// may execute.
+    fn permits(&self, ino: Ino, want: c_int, creds: &Credentials) -> bool {
+        let Some(inode) = self.inodes.get(&ino) else {
+            return true; // the standard streams
+        };
+        let meta = &inode.meta;
+        if creds.uid == 0 {
+            return want & libc::X_OK == 0 || self.entries(ino).is_some() || meta.mode & 0o111 != 0;
+        }
+        let bits = if creds.uid == meta.uid {
+            meta.mode >> 6
+        } else if creds.in_group(meta.gid) {
+            meta.mode >> 3
+        } else {
+            meta.mode
+        };
+        bits as c_int & want == want
+    }
+
+    /// Checks that `creds` may add or remove entries of directory `dir`.
+    fn check_writable_dir(&self, dir: Ino, creds: &Credentials) -> Result<(), c_int> {
+        if self.permits(dir, libc::W_OK | libc::X_OK, creds) {
+            Ok(())
+        } else {
+            Err(libc::EACCES)
+        }
+    }
+
+    /// Checks that `creds` may remove or replace the entry for `ino` in
+    /// directory `dir`. In a sticky directory, such as `/tmp`, only the
+    /// owner of either may.
+    fn check_removable(&self, dir: Ino, ino: Ino, creds: &Credentials) -> Result<(), c_int> {
+        self.check_writable_dir(dir, creds)?;
+        let sticky = self.inodes[&dir].meta.mode & libc::S_ISVTX != 0;
+        let owns = |ino: Ino| self.inodes[&ino].meta.uid == creds.uid;
+        if sticky && creds.uid != 0 && !owns(dir) && !owns(ino) {
+            return Err(libc::EPERM);
+        }
+        Ok(())
+    }
+
+    /// Sets the permission bits of `ino`, which only its owner may.
+    fn set_mode(&mut self, ino: Ino, mode: libc::mode_t) -> Result<(), c_int> {
+        let creds = credentials();
+        let inode = self.inodes.get_mut(&ino).ok_or(libc::EPERM)?;
+        if creds.uid != 0 && creds.uid != inode.meta.uid {
+            return Err(libc::EPERM);
+        }
+        inode.meta.mode = mode & 0o7777;
+        self.changed(ino);
+        Ok(())
+    }
+
+    /// Gives `ino` to `uid` and `gid`, either of which may be `-1` for no
+    /// change. Only root may give a file away; its owner may only change the
+    /// group, to one of their own.
+    fn set_owner(&mut self, ino: Ino, uid: libc::uid_t, gid: libc::gid_t) -> Result<(), c_int> {
+        let creds = credentials();
+        let meta = &mut self.inodes.get_mut(&ino).ok_or(libc::EPERM)?.meta;
+        let uid = if uid == libc::uid_t::MAX {
+            meta.uid
+        } else {
+            uid
+        };
+        let gid = if gid == libc::gid_t::MAX {
+            meta.gid
+        } else {
+            gid
+        };
+        let allowed = creds.uid == 0
+            || creds.uid == meta.uid && uid == meta.uid && (gid == meta.gid || creds.in_group(gid));
+        if !allowed {
+            return Err(libc::EPERM);
+        }
+        if creds.uid != 0 && meta.mode & 0o111 != 0 {
+            // as the kernel does, so no one can make a setuid file for others
+            meta.mode &= !(libc::S_ISUID | libc::S_ISGID);
+        }
+        (meta.uid, meta.gid) = (uid, gid);
+        self.changed(ino);
+        Ok(())
+    }
+
+    /// The content of `ino`, which has to be a regular file, or the errno
+    /// reading it fails with.
+    fn content(&self, ino: Ino) -> Result<&String, c_int> {
+        match self.node(ino) {
+            Some(Node::Regular(content)) => Ok(content),
+            Some(Node::Directory(_)) => Err(libc::EISDIR),
+            Some(Node::Symlink(_)) => Err(libc::EBADF),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn content_mut(&mut self, ino: Ino) -> Result<&mut String, c_int> {
+        match self.inodes.get_mut(&ino).map(|inode| &mut inode.node) {
+            Some(Node::Regular(content)) => Ok(content),
+            Some(Node::Directory(_)) => Err(libc::EISDIR),
+            Some(Node::Symlink(_)) => Err(libc::EBADF),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    /// Creates `file_type`, and for a directory everything in it, as `name`
+    /// in directory `dir`, owned by the process.
+    fn add(&mut self, dir: Ino, name: &str, file_type: FileType) -> Ino {
+        self.add_as(dir, name, file_type, &credentials())
+    }
+
+    /// Like [`Tree::add`], but owned by `owner`.
+    fn add_as(&mut self, dir: Ino, name: &str, file_type: FileType, owner: &Credentials) -> Ino {
+        let (node, mode, children) = match file_type {
+            FileType::Regular(content) => (Node::Regular(content), 0o644, HashMap::new()),
+            FileType::Directory(children) => (Node::Directory(HashMap::new()), 0o755, children),
+            FileType::Symlink(target) => (Node::Symlink(target), 0o777, HashMap::new()),
+        };
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let meta = Metadata::new(mode, owner, self.tick());
+        self.inodes.insert(
+            ino,
+            Inode {
+                node,
+                nlink: 0,
+                meta,
+            },
+        );
+        self.link(dir, name, ino);
+        for (name, file_type) in children {
+            self.add_as(ino, &name, file_type, owner);
+        }
+        ino
+    }
+
+    /// The directory `components` name from the root, creating those
+    /// missing, as `mkdir -p` does, owned by `owner`. Symlinks are not
+    /// followed.
+    fn make_dirs(&mut self, components: &[&str], owner: &Credentials) -> Result<Ino, c_int> {
+        let mut dir = ROOT_INO;
+        for component in components.iter().filter(|c| !c.is_empty()) {
+            dir = match self.entry(dir, component) {
+                Some(ino) => ino,
+                None => self.add_as(dir, component, FileType::Directory(HashMap::new()), owner),
+            };
+            if self.entries(dir).is_none() {
+                return Err(libc::ENOTDIR);
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Adds an entry `name` for `ino` to directory `dir`.
+    fn link(&mut self, dir: Ino, name: &str, ino: Ino) {
+        if self.set_entry(dir, name, ino) {
+            self.inodes.get_mut(&ino).unwrap().nlink += 1;
+            self.changed(ino);
+        }
+    }
+
+    /// Points `name` in directory `dir` at `ino`, without counting links.
+    /// Fails when `dir` is not a directory.
+    fn set_entry(&mut self, dir: Ino, name: &str, ino: Ino) -> bool {
+        match self.inodes.get_mut(&dir).map(|inode| &mut inode.node) {
+            Some(Node::Directory(entries)) => entries.insert(name.to_string(), ino),
+            _ => return false,
+        };
+        self.modified(dir);
+        if self.entries(ino).is_some() {
+            self.parents.insert(ino, dir);
+        }
+        true
+    }
+
+    /// Removes the entry `name` from directory `dir`, without counting links.
+    fn remove_entry(&mut self, dir: Ino, name: &str) -> Option<Ino> {
+        let ino = match self.inodes.get_mut(&dir).map(|inode| &mut inode.node) {
+            Some(Node::Directory(entries)) => entries.remove(name)?,
+            _ => return None,
+        };
+        self.modified(dir);
+        if self.parents.get(&ino) == Some(&dir) {
+            self.parents.remove(&ino);
+        }
+        Some(ino)
+    }
+
+    /// Removes the entry `name` from directory `dir`, freeing its inode if
+    /// that was its last name and no descriptor refers to it.
+    fn unlink(&mut self, dir: Ino, name: &str) -> Option<Ino> {
+        let ino = self.remove_entry(dir, name)?;
+        self.changed(ino);
+        let inode = self.inodes.get_mut(&ino).unwrap();
+        inode.nlink -= 1;
+        if inode.nlink == 0 {
+            self.reclaim();
+        }
+        Some(ino)
+    }
+
+    /// Advances the clock for a change, and returns the time of it.
+    fn tick(&mut self) -> libc::time_t {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Records that the content of `ino` changed, which changes its
+    /// metadata too.
+    fn modified(&mut self, ino: Ino) {
+        let now = self.tick();
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.meta.mtime = now;
+            inode.meta.ctime = now;
+        }
+    }
+
+    /// Records that the metadata of `ino` changed.
+    fn changed(&mut self, ino: Ino) {
+        let now = self.tick();
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.meta.ctime = now;
+        }
+    }
+
+    /// Frees the inodes that have neither names nor descriptors left. One
+    /// that was still open when it lost its last name goes with a later call.
+    fn reclaim(&mut self) {
+        let open: HashSet<Ino> = MockFs::current()
+            .inner
+            .fds
+            .lock()
+            .unwrap()
+            .fds
+            .values()
+            .map(|entry| entry.file.ino)
+            .collect();
+        self.inodes
+            .retain(|ino, inode| inode.nlink > 0 || open.contains(ino));
+        let inodes = &self.inodes;
+        self.parents.retain(|ino, _| inodes.contains_key(ino));
+    }
+}
+
+/// Describes inode `ino` in `st` as `stat` does.
+fn fill_stat(tree: &Tree, ino: Ino, st: &mut libc::stat) {
+    *st = unsafe { mem::zeroed() };
+    st.st_dev = MOCK_DEV;
+    st.st_ino = ino;
+    st.st_nlink = 1;
+    st.st_blksize = MOCK_BLKSIZE;
+    let Some(inode) = tree.inodes.get(&ino) else {
+        // the standard streams, which are not in the tree
+        st.st_mode = libc::S_IFCHR | 0o666;
+        return;
+    };
+    let (file_type, size) = match &inode.node {
+        Node::Regular(content) => (libc::S_IFREG, content.len()),
+        // one block of entries, as on ext4
+        Node::Directory(_) => (libc::S_IFDIR, MOCK_BLKSIZE as usize),
+        Node::Symlink(target) => (libc::S_IFLNK, target.len()),
+    };
+    let meta = &inode.meta;
+    st.st_mode = file_type | meta.mode;
+    st.st_nlink = inode.nlink as libc::nlink_t;
+    st.st_uid = meta.uid;
+    st.st_gid = meta.gid;
+    st.st_size = size as libc::off_t;
+    st.st_blocks = size.div_ceil(512) as libc::blkcnt_t;
+    st.st_atime = meta.atime;
+    st.st_mtime = meta.mtime;
+    st.st_ctime = meta.ctime;
+}
+
+/// Applies `change` to the inode `pathname` leads to, following a final
+/// symlink if `follow` is set.
+unsafe fn change_path(
+    pathname: *const c_char,
+    follow: bool,
+    change: impl FnOnce(&mut Tree, Ino) -> Result<(), c_int>,
+) -> c_int {
+    let path = CStr::from_ptr(pathname).to_str().unwrap_or("");
+    println!("change({}): FS_TREE.write()", path);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+    match lookup_ino(&fs_tree_lock, libc::AT_FDCWD, path, follow)
+        .and_then(|ino| change(&mut fs_tree_lock, ino))
+    {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+/// Applies `change` to the inode `fd` refers to.
+fn change_fd(fd: c_int, change: impl FnOnce(&mut Tree, Ino) -> Result<(), c_int>) -> c_int {
+    let Some(file) = open_file(fd) else {
+        set_errno(libc::EBADF);
+        return -1;
+    };
+    println!("change({}): FS_TREE.write()", fd);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+    match change(&mut fs_tree_lock, file.ino) {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+/// The path of directory `ino` to make the working directory, if it is a
+/// directory that may be searched.
+fn change_dir(tree: &Tree, ino: Ino) -> Result<String, c_int> {
+    if tree.entries(ino).is_none() {
+        return Err(libc::ENOTDIR);
+    }
+    if !tree.permits(ino, libc::X_OK, &credentials()) {
+        return Err(libc::EACCES);
+    }
+    tree.dir_path(ino).ok_or(libc::ENOENT)
+}
+
+/// The inode `path` relative to `dirfd` leads to, as `fstatat` finds it:
+/// a final symlink is followed if `follow` is set or the path ends in a
+/// slash.
+fn lookup_ino(tree: &Tree, dirfd: c_int, path: &str, follow: bool) -> Result<Ino, c_int> {
+    let flags = if follow || path.ends_with('/') {
+        libc::O_PATH
+    } else {
+        libc::O_PATH | libc::O_NOFOLLOW
+    };
+    let target = lookup_at(tree, dirfd, path, flags)?;
+    check_open(tree, &target, flags).map(|(_, ino)| ino)
+}
+
+/// The entries of directory `dir` as `getdents64` lists them: `.` and `..`
+/// first, then the rest by name. A directory that has been removed has none.
+fn listing(tree: &Tree, dir: Ino) -> Result<Vec<(String, Ino)>, c_int> {
+    let entries = tree.entries(dir).ok_or(libc::ENOTDIR)?;
+    if tree.dir_path(dir).is_none() {
+        return Ok(Vec::new());
+    }
+    let parent = tree.parents.get(&dir).copied().unwrap_or(dir);
+    let mut names: Vec<_> = entries
+        .iter()
+        .map(|(name, &ino)| (name.clone(), ino))
+        .collect();
+    names.sort();
+    let mut listing = vec![(".".to_string(), dir), ("..".to_string(), parent)];
+    listing.extend(names);
+    Ok(listing)
+}
+
+/// The `d_type` of `ino`.
+fn dirent_type(tree: &Tree, ino: Ino) -> u8 {
+    match tree.node(ino) {
+        Some(Node::Regular(_)) => libc::DT_REG,
+        Some(Node::Directory(_)) => libc::DT_DIR,
+        Some(Node::Symlink(_)) => libc::DT_LNK,
+        None => libc::DT_UNKNOWN,
+    }
+}
+
+/// Where a lookup of `path` relative to `dirfd` starts: EBADF for a
+/// descriptor that is not open, ENOENT for a directory since removed.
+fn base_path(tree: &Tree, dirfd: c_int, path: &str) -> Result<String, c_int> {
+    if path.starts_with("/") {
+        Ok("".to_string())
+    } else if dirfd == libc::AT_FDCWD {
+        Ok(MockFs::current().inner.cwd.lock().unwrap().clone())
+    } else {
+        fd_path(tree, dirfd)
+    }
+}
+
+/// Where an open of a path lands.
+#[derive(Debug)]
+enum Target {
+    /// An existing inode; a symlink only when opened with `O_NOFOLLOW`.
+    Found {
+        path: String,
+        ino: Ino,
+        wants_dir: bool,
+    },
+    /// Nothing, but the directory `dir` it would be created in exists.
+    Missing {
+        path: String,
+        dir: Ino,
+        wants_dir: bool,
+    },
+}
+
+/// Resolves `path` relative to `dirfd` as `openat` with `flags` would.
+fn lookup_at(tree: &Tree, dirfd: c_int, path: &str, flags: c_int) -> Result<Target, c_int> {
+    let magic_target;
+    let base_path = base_path(tree, dirfd, path)?;
+    let mut full_components = parse_path(&base_path);
+    full_components.extend(path.split('/').filter(|&c| !c.is_empty() && c != "."));
+
+    // `/proc/self/fd/N` is a magic link to whatever the descriptor refers to
+    if let Some((fd, rest)) = proc_fd(&full_components) {
+        if rest.is_empty() && flags & libc::O_NOFOLLOW != 0 {
+            return Err(libc::ELOOP);
+        }
+        magic_target = fd_path(tree, fd)?;
+        let mut expanded = parse_path(&magic_target);
+        expanded.extend_from_slice(rest);
+        full_components = expanded;
+    }
+
+    let wants_dir = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+    let mut target = open_target(tree, &full_components, flags, &mut 0)?;
+    match &mut target {
+        Target::Found { wants_dir: w, .. } | Target::Missing { wants_dir: w, .. } => *w = wants_dir,
+    }
+    Ok(target)
+}
+
+/// Resolves `components` from the root, following a final symlink unless
+/// `O_NOFOLLOW` is set.
+fn open_target(
+    tree: &Tree,
+    components: &[&str],
+    flags: c_int,
+    hops: &mut usize,
+) -> Result<Target, c_int> {
+    let (ino, path) = match traverse_path(tree, components, hops) {
+        Ok(found) => found,
+        Err(libc::ENOENT) => return missing_target(tree, components, flags, hops),
+        Err(errno) => return Err(errno),
+    };
+    let is_symlink = matches!(tree.inodes[&ino].node, Node::Symlink(_));
+    if is_symlink && flags & (libc::O_CREAT | libc::O_EXCL) == libc::O_CREAT | libc::O_EXCL {
+        return Err(libc::EEXIST);
+    }
+    match &tree.inodes[&ino].node {
+        Node::Symlink(target_path) if flags & libc::O_NOFOLLOW == 0 => {
+            *hops += 1;
+            if *hops
+                > MockFs::current()
+                    .inner
+                    .max_symlink_hops
+                    .load(Ordering::Relaxed)
+            {
+                return Err(libc::ELOOP);
+            }
+            // a relative target is looked up from the directory of the link
+            let mut target_components = if target_path.starts_with('/') {
+                Vec::new()
+            } else {
+                let mut parent = parse_path(&path);
+                parent.pop();
+                parent
+            };
+            target_components.extend(target_path.split('/').filter(|c| !c.is_empty()));
+            open_target(tree, &target_components, flags, hops)
+        }
+        Node::Symlink(_) if flags & libc::O_PATH == 0 => Err(libc::ELOOP),
+        _ => Ok(Target::Found {
+            path,
+            ino,
+            wants_dir: false,
+        }),
+    }
+}
+
+/// The target of `components` when [`traverse_path`] finds nothing there:
+/// only the last component may be missing, and it has to be a name.
+fn missing_target(
+    tree: &Tree,
+    components: &[&str],
+    flags: c_int,
+    hops: &mut usize,
+) -> Result<Target, c_int> {
+    let Some((&name, parent)) = components.split_last() else {
+        return Err(libc::ENOENT);
+    };
+    if name == ".." {
+        return Err(libc::ENOENT);
+    }
+    let (dir, parent_path) = traverse_path(tree, parent, hops)?;
+    match &tree.inodes[&dir].node {
+        Node::Directory(_) => Ok(Target::Missing {
+            path: join_path(&parent_path, name),
+            dir,
+            wants_dir: false,
+        }),
+        Node::Symlink(_) => {
+            // the parent is a link to follow
+            match open_target(tree, parent, flags & !libc::O_NOFOLLOW, hops)? {
+                Target::Found { ino, path, .. } if tree.entries(ino).is_some() => {
+                    Ok(Target::Missing {
+                        path: join_path(&path, name),
+                        dir: ino,
+                        wants_dir: false,
+                    })
+                }
+                Target::Found { .. } => Err(libc::ENOTDIR),
+                Target::Missing { .. } => Err(libc::ENOENT),
+            }
+        }
+        Node::Regular(_) => Err(libc::ENOTDIR),
+    }
+}
+
+/// Checks that `target` can be opened with `flags` without changing the
+/// tree, and returns its path and inode.
+fn check_open(tree: &Tree, target: &Target, flags: c_int) -> Result<(String, Ino), c_int> {
+    let (path, ino, wants_dir) = match target {
+        Target::Missing { .. } => return Err(libc::ENOENT),
+        Target::Found {
+            path,
+            ino,
+            wants_dir,
+        } => (path, *ino, *wants_dir),
+    };
+    let is_dir = tree.entries(ino).is_some();
+    if flags & libc::O_CREAT != 0 && flags & libc::O_EXCL != 0 {
+        return Err(libc::EEXIST);
+    }
+    if (wants_dir || flags & libc::O_DIRECTORY != 0) && !is_dir {
+        return Err(libc::ENOTDIR);
+    }
+    let writes = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0;
+    if is_dir && flags & libc::O_PATH == 0 && (writes || flags & libc::O_CREAT != 0) {
+        return Err(libc::EISDIR);
+    }
+    if flags & libc::O_PATH == 0 && !tree.permits(ino, access_wanted(flags), &credentials()) {
+        return Err(libc::EACCES);
+    }
+    Ok((path.clone(), ino))
+}
+
+/// The `R_OK` and `W_OK` an open with `flags` needs.
+fn access_wanted(flags: c_int) -> c_int {
+    let wanted = match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => libc::R_OK,
+        libc::O_WRONLY => libc::W_OK,
+        _ => libc::R_OK | libc::W_OK,
+    };
+    if flags & libc::O_TRUNC != 0 {
+        wanted | libc::W_OK
+    } else {
+        wanted
+    }
+}
+
+/// Creates `target` with `mode` or truncates it as `O_CREAT` and `O_TRUNC`
+/// ask, then checks it like [`check_open`].
+fn prepare_open(
+    tree: &mut Tree,
+    target: Target,
+    flags: c_int,
+    mode: libc::mode_t,
+) -> Result<(String, Ino), c_int> {
+    match target {
+        Target::Missing {
+            path,
+            dir,
+            wants_dir,
+        } if flags & libc::O_CREAT != 0 => {
+            if wants_dir || flags & libc::O_DIRECTORY != 0 {
+                return Err(if wants_dir {
+                    libc::EISDIR
+                } else {
+                    libc::EINVAL
+                });
+            }
+            tree.check_writable_dir(dir, &credentials())?;
+            let name = parse_path(&path).pop().unwrap().to_string();
+            let ino = tree.add(dir, &name, FileType::Regular(String::new()));
+            tree.inodes.get_mut(&ino).unwrap().meta.mode = mode & 0o7777 & !MOCK_UMASK;
+            Ok((path, ino))
+        }
+        target => {
+            let (path, ino) = check_open(tree, &target, flags)?;
+            if flags & libc::O_TRUNC != 0 {
+                if let Ok(content) = tree.content_mut(ino) {
+                    content.clear();
+                    tree.modified(ino);
+                }
+            }
+            Ok((path, ino))
+        }
+    }
+}
+
+/// Creates `file_type` as `path` relative to `dirfd`, as `mkdirat` and
+/// `symlinkat` do: the parent has to exist and the name must not.
+fn add_entry(tree: &mut Tree, dirfd: c_int, path: &str, file_type: FileType) -> Result<Ino, c_int> {
+    let (dir, name) = match lookup_parent(tree, dirfd, path) {
+        Err(libc::EBUSY | libc::EINVAL) => return Err(libc::EEXIST),
+        found => found?,
+    };
+    if tree.entry(dir, name).is_some() {
+        return Err(libc::EEXIST);
+    }
+    // a trailing slash only names a directory
+    if path.ends_with('/') && !matches!(file_type, FileType::Directory(_)) {
+        return Err(libc::ENOENT);
+    }
+    tree.check_writable_dir(dir, &credentials())?;
+    Ok(tree.add(dir, name, file_type))
+}
+
+/// Removes the entry `path` relative to `dirfd` names, as `unlinkat` with
+/// `flags` does.
+fn unlink_entry(tree: &mut Tree, dirfd: c_int, path: &str, flags: c_int) -> Result<(), c_int> {
+    if flags & !libc::AT_REMOVEDIR != 0 {
+        return Err(libc::EINVAL);
+    }
+    let (dir, name) = match lookup_parent(tree, dirfd, path) {
+        // `unlink` of "." or ".." names a directory like any other
+        Err(libc::EINVAL) if flags == 0 => return Err(libc::EISDIR),
+        found => found?,
+    };
+    let ino = tree.entry(dir, name).ok_or(libc::ENOENT)?;
+    match (flags & libc::AT_REMOVEDIR != 0, tree.entries(ino)) {
+        (false, Some(_)) => return Err(libc::EISDIR),
+        (true, None) => return Err(libc::ENOTDIR),
+        (true, Some(entries)) if !entries.is_empty() => return Err(libc::ENOTEMPTY),
+        _ => {}
+    }
+    if path.ends_with('/') && tree.entries(ino).is_none() {
+        return Err(libc::ENOTDIR);
+    }
+    tree.check_removable(dir, ino, &credentials())?;
+    tree.unlink(dir, name);
+    Ok(())
+}
+
+/// Moves the entry `old` names to where `new` names, as `renameat2` with
+/// `flags` does, or swaps the two with `RENAME_EXCHANGE`.
+fn rename_entry(
+    tree: &mut Tree,
+    (olddirfd, old): (c_int, &str),
+    (newdirfd, new): (c_int, &str),
+    flags: c_uint,
+) -> Result<(), c_int> {
+    let exchange = flags & libc::RENAME_EXCHANGE != 0;
+    if flags & !(libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE) != 0
+        || exchange && flags & libc::RENAME_NOREPLACE != 0
+    {
+        return Err(libc::EINVAL);
+    }
+    let (old_dir, old_name) = lookup_parent(tree, olddirfd, old)?;
+    let (new_dir, new_name) = lookup_parent(tree, newdirfd, new)?;
+    let ino = tree.entry(old_dir, old_name).ok_or(libc::ENOENT)?;
+    let target = tree.entry(new_dir, new_name);
+    // a directory cannot go beneath itself
+    let is_dir = |ino| tree.entries(ino).is_some();
+    if is_dir(ino) && tree.is_within(new_dir, ino) && target != Some(ino) {
+        return Err(libc::EINVAL);
+    }
+    let creds = credentials();
+    tree.check_removable(old_dir, ino, &creds)?;
+    match target {
+        Some(target) => tree.check_removable(new_dir, target, &creds)?,
+        None => tree.check_writable_dir(new_dir, &creds)?,
+    }
+    // a directory moved elsewhere gets a new `..` entry
+    if is_dir(ino) && old_dir != new_dir && !tree.permits(ino, libc::W_OK, &creds) {
+        return Err(libc::EACCES);
+    }
+
+    if exchange {
+        let target = target.ok_or(libc::ENOENT)?;
+        if is_dir(target) && tree.is_within(old_dir, target) {
+            return Err(libc::EINVAL);
+        }
+        tree.set_entry(old_dir, old_name, target);
+        tree.set_entry(new_dir, new_name, ino);
+        tree.changed(ino);
+        tree.changed(target);
+        return Ok(());
+    }
+    match target {
+        Some(_) if flags & libc::RENAME_NOREPLACE != 0 => return Err(libc::EEXIST),
+        // two names for the same file: nothing to do
+        Some(target) if target == ino => return Ok(()),
+        Some(target) => match (is_dir(ino), tree.entries(target)) {
+            (true, None) => return Err(libc::ENOTDIR),
+            (false, Some(_)) => return Err(libc::EISDIR),
+            (true, Some(entries)) if !entries.is_empty() => return Err(libc::ENOTEMPTY),
+            _ => {
+                tree.unlink(new_dir, new_name);
+            }
+        },
+        None => {}
+    }
+    tree.remove_entry(old_dir, old_name);
+    tree.set_entry(new_dir, new_name, ino);
+    tree.changed(ino);
+    Ok(())
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+fn allocate_fd(resolved_path: String, ino: Ino, flags: c_int) -> c_int {
+    let entry = FdEntry {
+        file: Arc::new(OpenFile {
+            path: resolved_path,
+            ino,
+            flags,
+            offset: Mutex::new(0),
+        }),
+        cloexec: flags & libc::O_CLOEXEC != 0,
+    };
+    let fs = MockFs::current();
+    let fd = fs.inner.fds.lock().unwrap().install(0, entry);
+    fd
+}
+
+fn open_file(fd: c_int) -> Option<Arc<OpenFile>> {
+    let fs = MockFs::current();
+    let table = fs.inner.fds.lock().unwrap();
+    table.fds.get(&fd).map(|entry| entry.file.clone())
+}
+
+/// The path `fd` refers to, which `/proc/self/fd/<fd>` points to. An open
+/// directory is followed wherever it is renamed to, as the kernel does; any
+/// other file keeps the path it was opened by.
+fn fd_path(tree: &Tree, fd: c_int) -> Result<String, c_int> {
+    let file = open_file(fd).ok_or(libc::EBADF)?;
+    if tree.entries(file.ino).is_some() {
+        tree.dir_path(file.ino).ok_or(libc::ENOENT)
+    } else {
+        Ok(file.path.clone())
+    }
+}
+
+/// The open file behind `fd` if it was opened for `access` (`O_RDONLY` to
+/// read, `O_WRONLY` to write), or the errno to fail with.
+fn open_file_for(fd: c_int, access: c_int) -> Result<Arc<OpenFile>, c_int> {
+    let file = open_file(fd).ok_or(libc::EBADF)?;
+    if file.flags & libc::O_PATH != 0 {
+        return Err(libc::EBADF);
+    }
+    match file.flags & libc::O_ACCMODE {
+        libc::O_RDWR => Ok(file),
+        mode if mode == access => Ok(file),
+        _ => Err(libc::EBADF),
+    }
+}
+
+/// Writes `data` into `content` at `offset`, filling any gap with NULs.
+/// Contents are kept as text, so a write that leaves invalid UTF-8 behind
+/// fails with EINVAL and changes nothing.
+fn write_at(content: &mut String, offset: usize, data: &[u8]) -> Result<(), c_int> {
+    let mut bytes = content.clone().into_bytes();
+    let end = offset + data.len();
+    if bytes.len() < end {
+        bytes.resize(end, 0);
+    }
+    bytes[offset..end].copy_from_slice(data);
+    *content = String::from_utf8(bytes).map_err(|_| libc::EINVAL)?;
+    Ok(())
+}
+
+/// Reads at `at`, or at and past the file offset when `at` is `None`.
+unsafe fn read_file(fd: c_int, buf: *mut c_void, count: usize, at: Option<usize>) -> isize {
+    let file = match open_file_for(fd, libc::O_RDONLY) {
+        Ok(file) => file,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+    println!("read({}): FS_TREE.read()", fd);
+    let fs = MockFs::current();
+    let fs_tree_lock = fs.inner.tree.read().unwrap();
+    let content = match fs_tree_lock.content(file.ino) {
+        Ok(content) => content,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+
+    let mut offset = file.offset.lock().unwrap();
+    let start = at.unwrap_or(*offset).min(content.len());
+    let length = count.min(content.len() - start);
+    slice::from_raw_parts_mut(buf as *mut u8, length)
+        .copy_from_slice(&content.as_bytes()[start..start + length]);
+    if at.is_none() {
+        *offset = start + length;
+    }
+    length as isize
+}
+
+/// Writes at `at`, or at and past the file offset when `at` is `None`.
+/// `O_APPEND` writes always go to the end, `pwrite` included, as on Linux.
+unsafe fn write_file(fd: c_int, buf: *const c_void, count: usize, at: Option<usize>) -> isize {
+    let file = match open_file_for(fd, libc::O_WRONLY) {
+        Ok(file) => file,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+    let data = slice::from_raw_parts(buf as *const u8, count);
+    println!("write({}): FS_TREE.write()", fd);
+    let fs = MockFs::current();
+    let mut fs_tree_lock = fs.inner.tree.write().unwrap();
+    let content = match fs_tree_lock.content_mut(file.ino) {
+        Ok(content) => content,
+        Err(errno) => {
+            set_errno(errno);
+            return -1;
+        }
+    };
+
+    let mut offset = file.offset.lock().unwrap();
+    let start = if file.flags & libc::O_APPEND != 0 {
+        content.len()
+    } else {
+        at.unwrap_or(*offset)
+    };
+    if let Err(errno) = write_at(content, start, data) {
+        set_errno(errno);
+        return -1;
+    }
+    if at.is_none() {
+        *offset = start + count;
+    }
+    fs_tree_lock.modified(file.ino);
+    count as isize
+}
+
+/// Splits a `proc/self/fd/N/...` path into the descriptor and the rest.
+fn proc_fd<'a, 'b>(components: &'a [&'b str]) -> Option<(c_int, &'a [&'b str])> {
+    match components {
+        ["proc", "self", "fd", fd, rest @ ..] => Some((fd.parse().ok()?, rest)),
+        _ => None,
+    }
+}
+
+/// The error number of the last failed mock call on this thread, which is
+/// what `errno` would be after the real one. Like libc, successful calls
+/// leave it alone.
+pub fn errno() -> c_int {
+    ERRNO.with(|errno| errno.get())
+}
+
+/// Sets this thread's mock errno. The real one is left alone, so it cannot
+/// be mistaken for the result of a mock call, nor the other way round.
+fn set_errno(errno: c_int) {
+    ERRNO.with(|cell| cell.set(errno));
+}
+
+#[allow(dead_code)]
+fn convert_relative_to_absolute_path(relative_path: &str) -> String {
+    if relative_path.starts_with("/") {
+        // Already an absolute path, return as is.
+        relative_path.to_string()
     } else if relative_path == "." {
         // The current directory is requested.
-        CURRENT_DIR.with(|v| v.borrow().clone()).to_string()
+        MockFs::current()
+            .inner
+            .cwd
+            .lock()
+            .unwrap()
+            .clone()
+            .to_string()
     } else {
         // A relative path is given, join it with the current directory.
-        let mut path = CURRENT_DIR.with(|v| v.borrow().clone()).clone();
+        let mut path = MockFs::current().inner.cwd.lock().unwrap().clone().clone();
         if !path.ends_with("/") {
             path.push('/');
         }
@@ -313,29 +2206,62 @@ fn convert_relative_to_absolute_path(relative_path: &str) -> String {
     }
 }
 
-fn traverse_path<'a>(
-    root: &'a HashMap<String, FileType>,
+/// Resolves `components` from the root, following symlinks in the middle of
+/// the path. `hops` counts the links followed so far across recursive
+/// lookups. Fails with ENOENT, ENOTDIR, ELOOP or EACCES as a path lookup in
+/// the kernel does. A lookup relative to a descriptor goes through the
+/// directories above it too, so those have to be searchable as well, which
+/// the kernel does not require.
+fn traverse_path(
+    tree: &Tree,
     components: &[&str],
-) -> Option<(FileType, String)> {
-    let mut current = root;
+    hops: &mut usize,
+) -> Result<(Ino, String), c_int> {
+    // the directories from the root down to the current one, and their names
+    let mut dirs = vec![ROOT_INO];
+    let mut names: Vec<&str> = Vec::new();
     let mut path = Vec::from(components);
-    let mut resolved_path = String::new();
-
-    while let Some(component) = path.first() {
-        resolved_path.push_str("/");
-        resolved_path.push_str(component);
+    let creds = credentials();
 
-        if *component == "." {
+    while let Some(&component) = path.first() {
+        if component.is_empty() || component == "." {
+            path.remove(0);
+            continue;
+        }
+        // every directory looked in has to be searchable
+        if !tree.permits(*dirs.last().unwrap(), libc::X_OK, &creds) {
+            return Err(libc::EACCES);
+        }
+        if component == ".." {
+            // the root is its own parent
+            if dirs.len() > 1 {
+                dirs.pop();
+                names.pop();
+            }
             path.remove(0);
             continue;
         }
 
-        match current.get(*component) {
-            Some(FileType::Directory(ref subdir)) if path.len() > 1 => {
-                current = subdir;
+        let current = *dirs.last().unwrap();
+        let Some(ino) = tree.entry(current, component) else {
+            return Err(libc::ENOENT);
+        };
+        match &tree.inodes[&ino].node {
+            Node::Directory(_) if path.len() > 1 => {
+                dirs.push(ino);
+                names.push(component);
                 path.remove(0);
             }
-            Some(FileType::Symlink(target)) if path.len() > 1 => {
+            Node::Symlink(target) if path.len() > 1 => {
+                *hops += 1;
+                if *hops
+                    > MockFs::current()
+                        .inner
+                        .max_symlink_hops
+                        .load(Ordering::Relaxed)
+                {
+                    return Err(libc::ELOOP);
+                }
                 let mut target_components = target
                     .split('/')
                     .filter(|c| !c.is_empty())
@@ -343,69 +2269,82 @@ fn traverse_path<'a>(
                 path.remove(0);
                 target_components.append(&mut path);
                 path = target_components;
-                current = root; // Restart from root because symlink target is an absolute path
-                resolved_path = String::new();
+                if target.starts_with('/') {
+                    // Restart from root because symlink target is an absolute path
+                    dirs.truncate(1);
+                    names.clear();
+                }
             }
-            Some(file_type) if path.len() == 1 => {
-                return Some((file_type.clone(), resolved_path));
+            _ if path.len() == 1 => {
+                names.push(component);
+                return Ok((ino, format!("/{}", names.join("/"))));
             }
-            _ => return None,
+            _ => return Err(libc::ENOTDIR),
         }
     }
 
-    Some((FileType::Directory(current.clone()), resolved_path))
+    Ok((*dirs.last().unwrap(), format!("/{}", names.join("/"))))
 }
 
-fn traverse_path_recursive<'a>(
-    root: &'a HashMap<String, FileType>,
-    components: &[&str],
-    flags: i32,
-) -> Option<(FileType, String)> {
-    let (file_type, path) = traverse_path(root, components)?;
-
-    match file_type {
-        FileType::Symlink(target_path) => {
-            if flags == libc::O_NOFOLLOW {
-                return None;
-            }
-            let target_components = target_path
-                .split('/')
-                .filter(|c| !c.is_empty())
-                .collect::<Vec<_>>();
-            traverse_path_recursive(root, &target_components, flags)
-        }
-        _ => Some((file_type, path)),
+/// The directory an entry for `path` relative to `dirfd` goes in or comes
+/// out of, following symlinks on the way there, and the name of that entry.
+fn lookup_parent<'a>(tree: &Tree, dirfd: c_int, path: &'a str) -> Result<(Ino, &'a str), c_int> {
+    let base_path = base_path(tree, dirfd, path)?;
+    let mut components = parse_path(&base_path);
+    let Some(&name) = parse_path(path).last() else {
+        return Err(libc::EBUSY); // the root
+    };
+    if name == "." || name == ".." {
+        return Err(libc::EINVAL);
     }
+    components.extend(parse_path(path));
+    components.pop();
+    let (dir, _) = traverse_path(tree, &components, &mut 0)?;
+    if tree.entries(dir).is_none() {
+        return Err(libc::ENOTDIR);
+    }
+    Ok((dir, name))
 }
 
-fn traverse_path_mut<'a>(
-    current_dir: &'a mut HashMap<String, FileType>,
-    components: &[&str],
-) -> Option<&'a mut FileType> {
-    let mut current = current_dir;
-    let path_len = components.len();
-
-    for (index, &component) in components.iter().enumerate() {
-        if let Some(next) = current.get_mut(component) {
-            if index == path_len - 1 {
-                return Some(next);
-            }
+fn parse_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|&c| !c.is_empty()).collect()
+}
 
-            if let FileType::Directory(ref mut subdir) = next {
-                current = subdir;
-            } else {
-                // A non-directory component was found where a directory was expected
-                return None;
-            }
-        } else {
-            // Component not found
-            return None;
-        }
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symlink_loop() {
+        create("/loop/a", FileType::Symlink("/loop/b".to_string())).unwrap();
+        create("/loop/b", FileType::Symlink("/loop/a".to_string())).unwrap();
+        create("/loop/dir/file", FileType::Regular(String::new())).unwrap();
+        create("/loop/c", FileType::Symlink("/loop/dir".to_string())).unwrap();
+
+        let open_path = |path: &str| unsafe { open(CString::new(path).unwrap().as_ptr(), 0) };
+        assert_eq!(open_path("/loop/a"), -1);
+        assert_eq!(open_path("/loop/a/file"), -1);
+        assert_ne!(open_path("/loop/c/file"), -1);
     }
 
-    None
-}
+    #[test]
+    fn test_dot_dot_and_relative_links() {
+        create("/dots/dir/sub/file", FileType::Regular(String::new())).unwrap();
+        create("/dots/dir/up", FileType::Symlink("..".to_string())).unwrap();
+        create("/dots/link", FileType::Symlink("dir/sub".to_string())).unwrap();
 
-fn parse_path(path: &str) -> Vec<&str> {
-    path.split('/').filter(|&c| !c.is_empty()).collect()
+        let resolve = |path: &str| {
+            let fd = unsafe { open(CString::new(path).unwrap().as_ptr(), 0) };
+            assert_ne!(fd, -1, "{}", path);
+            read_link(format!("/proc/self/fd/{}", fd)).unwrap()
+        };
+        assert_eq!(
+            resolve("/dots/dir/sub/../sub/./file"),
+            Path::new("/dots/dir/sub/file")
+        );
+        assert_eq!(resolve("/dots/link/file"), Path::new("/dots/dir/sub/file"));
+        assert_eq!(resolve("/dots/link/.."), Path::new("/dots/dir"));
+        assert_eq!(resolve("/dots/dir/up/link"), Path::new("/dots/dir/sub"));
+        assert_eq!(resolve("/../.."), Path::new("/"));
+    }
 }
@@ -0,0 +1,418 @@
+//! Trees to set up a mock file system with, built in code or written in a
+//! small text format, one entry per line:
+//!
+//! ```text
+//! # a comment
+//! /etc/                           a directory
+//! /etc/passwd "root:x:0:0\n"      a file and its content
+//! /etc/empty                      an empty file
+//! /etc/link -> passwd             a symlink and its target
+//! /etc/alias => /etc/passwd       a hard link to an entry above
+//! /home/alice/ mode=0700 owner=1000:1000
+//! ```
+//!
+//! Paths are absolute, and cannot contain spaces. Missing parent
+//! directories are created. Anything without an `owner` is owned by root,
+//! and anything without a `mode` gets the usual 0644 or 0755. Contents take
+//! the escapes `\n`, `\t`, `\"` and `\\`.
+
+use super::{parse_path, Credentials, FileType, MockFs, Tree, ROOT_INO};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Directory,
+    Regular(String),
+    Symlink(String),
+    /// Another name for the entry at the path.
+    HardLink(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    path: String,
+    kind: Kind,
+    mode: Option<libc::mode_t>,
+    owner: Option<(libc::uid_t, libc::gid_t)>,
+}
+
+/// A tree to apply to a [`MockFs`], entry by entry in the order given.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fixture {
+    entries: Vec<Entry>,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Fixture::default()
+    }
+
+    /// Reads and parses a fixture file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FixtureError> {
+        fs::read_to_string(path).map_err(FixtureError::Io)?.parse()
+    }
+
+    pub fn dir(self, path: &str) -> Self {
+        self.with(path, Kind::Directory)
+    }
+
+    pub fn file(self, path: &str, content: &str) -> Self {
+        self.with(path, Kind::Regular(content.to_string()))
+    }
+
+    pub fn symlink(self, path: &str, target: &str) -> Self {
+        self.with(path, Kind::Symlink(target.to_string()))
+    }
+
+    /// Makes `path` another name for the entry at `target`, which has to be
+    /// a file reached without symlinks.
+    pub fn hard_link(self, path: &str, target: &str) -> Self {
+        self.with(path, Kind::HardLink(target.to_string()))
+    }
+
+    /// Sets the permission bits of the entry added last.
+    ///
+    /// # Panics
+    ///
+    /// If there is no entry yet.
+    pub fn mode(mut self, mode: libc::mode_t) -> Self {
+        self.last().mode = Some(mode);
+        self
+    }
+
+    /// Sets the owner of the entry added last.
+    ///
+    /// # Panics
+    ///
+    /// If there is no entry yet.
+    pub fn owner(mut self, uid: libc::uid_t, gid: libc::gid_t) -> Self {
+        self.last().owner = Some((uid, gid));
+        self
+    }
+
+    /// A new [`MockFs`] holding the tree.
+    pub fn build(&self) -> Result<MockFs, FixtureError> {
+        let fs = MockFs::new();
+        fs.enter(|| self.apply())?;
+        Ok(fs)
+    }
+
+    /// Adds the tree to the current [`MockFs`], all of it or, on an error,
+    /// none of it.
+    pub fn apply(&self) -> Result<(), FixtureError> {
+        let fs = MockFs::current();
+        let mut tree = fs.inner.tree.write().unwrap();
+        let mut applied = tree.clone();
+        for entry in &self.entries {
+            apply_entry(&mut applied, entry).map_err(|errno| FixtureError::Apply {
+                path: entry.path.clone(),
+                errno,
+            })?;
+        }
+        *tree = applied;
+        Ok(())
+    }
+
+    fn with(mut self, path: &str, kind: Kind) -> Self {
+        self.entries.push(Entry {
+            path: path.to_string(),
+            kind,
+            mode: None,
+            owner: None,
+        });
+        self
+    }
+
+    fn last(&mut self) -> &mut Entry {
+        self.entries.last_mut().expect("no entry to set")
+    }
+}
+
+fn apply_entry(tree: &mut Tree, entry: &Entry) -> Result<(), c_int> {
+    let root = Credentials::root();
+    let components = parse_path(&entry.path);
+    // the tree would take them as names rather than follow them
+    if components.iter().any(|&name| name == "." || name == "..") {
+        return Err(libc::EINVAL);
+    }
+    let ino = match components.split_last() {
+        None if entry.kind == Kind::Directory => ROOT_INO,
+        None => return Err(libc::EEXIST),
+        Some((name, parents)) => {
+            let dir = tree.make_dirs(parents, &root)?;
+            match (&entry.kind, tree.entry(dir, name)) {
+                (Kind::Directory, Some(ino)) if tree.entries(ino).is_some() => ino,
+                (_, Some(_)) => return Err(libc::EEXIST),
+                (Kind::Directory, None) => {
+                    tree.add_as(dir, name, FileType::Directory(HashMap::new()), &root)
+                }
+                (Kind::Regular(content), None) => {
+                    tree.add_as(dir, name, FileType::Regular(content.clone()), &root)
+                }
+                (Kind::Symlink(target), None) => {
+                    tree.add_as(dir, name, FileType::Symlink(target.clone()), &root)
+                }
+                (Kind::HardLink(target), None) => {
+                    let ino = parse_path(target)
+                        .iter()
+                        .try_fold(ROOT_INO, |dir, name| tree.entry(dir, name))
+                        .ok_or(libc::ENOENT)?;
+                    if tree.entries(ino).is_some() {
+                        return Err(libc::EPERM);
+                    }
+                    tree.link(dir, name, ino);
+                    ino
+                }
+            }
+        }
+    };
+    let meta = &mut tree.inodes.get_mut(&ino).unwrap().meta;
+    if let Some(mode) = entry.mode {
+        meta.mode = mode & 0o7777;
+    }
+    if let Some((uid, gid)) = entry.owner {
+        (meta.uid, meta.gid) = (uid, gid);
+    }
+    Ok(())
+}
+
+impl FromStr for Fixture {
+    type Err = FixtureError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut fixture = Fixture::new();
+        for (index, line) in text.lines().enumerate() {
+            let parse_error = |message: &str| FixtureError::Parse {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            let tokens = tokenize(line).map_err(&parse_error)?;
+            let Some((path, rest)) = tokens.split_first() else {
+                continue;
+            };
+            let Token::Word(path) = path else {
+                return Err(parse_error("expected a path"));
+            };
+            if !path.starts_with('/') {
+                return Err(parse_error("paths have to be absolute"));
+            }
+            if path.split('/').any(|name| name == "." || name == "..") {
+                return Err(parse_error("paths cannot have `.` or `..` in them"));
+            }
+
+            let (kind, attributes) = match rest {
+                [Token::Word(arrow), Token::Word(target), attributes @ ..]
+                    if arrow == "->" || arrow == "=>" =>
+                {
+                    if path.ends_with('/') {
+                        return Err(parse_error("a link cannot end in `/`"));
+                    }
+                    let kind = if arrow == "->" {
+                        Kind::Symlink(target.clone())
+                    } else {
+                        Kind::HardLink(target.clone())
+                    };
+                    (kind, attributes)
+                }
+                [Token::Word(arrow), ..] if arrow == "->" || arrow == "=>" => {
+                    return Err(parse_error("a link needs a target"));
+                }
+                [Token::Quoted(_), ..] if path.ends_with('/') => {
+                    return Err(parse_error("a directory has no content"));
+                }
+                [Token::Quoted(content), attributes @ ..] => {
+                    (Kind::Regular(content.clone()), attributes)
+                }
+                attributes if path.ends_with('/') => (Kind::Directory, attributes),
+                attributes => (Kind::Regular(String::new()), attributes),
+            };
+            fixture = fixture.with(path.trim_end_matches('/'), kind);
+            let entry = fixture.last();
+            if entry.path.is_empty() {
+                entry.path = "/".to_string();
+            }
+
+            for attribute in attributes {
+                let Token::Word(attribute) = attribute else {
+                    return Err(parse_error("unexpected content"));
+                };
+                match attribute.split_once('=') {
+                    Some(("mode", _)) if matches!(entry.kind, Kind::Symlink(_)) => {
+                        return Err(parse_error("a symlink has no mode"));
+                    }
+                    Some(("mode", mode)) => {
+                        let mode = libc::mode_t::from_str_radix(mode, 8)
+                            .ok()
+                            .filter(|&mode| mode <= 0o7777)
+                            .ok_or_else(|| parse_error("mode is not an octal mode"))?;
+                        entry.mode = Some(mode);
+                    }
+                    Some(("owner", owner)) => {
+                        let owner = owner
+                            .split_once(':')
+                            .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+                            .ok_or_else(|| parse_error("owner is not UID:GID"))?;
+                        entry.owner = Some(owner);
+                    }
+                    _ => return Err(parse_error(&format!("unknown `{}`", attribute))),
+                }
+            }
+        }
+        Ok(fixture)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+}
+
+/// Splits a line into words and quoted strings, up to a `#` outside quotes.
+fn tokenize(line: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' => break,
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated string"),
+                        Some('"') => break,
+                        Some('\\') => quoted.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(c @ ('"' | '\\')) => c,
+                            _ => return Err("unknown escape"),
+                        }),
+                        Some(c) => quoted.push(c),
+                    }
+                }
+                tokens.push(Token::Quoted(quoted));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+pub enum FixtureError {
+    Io(io::Error),
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// An entry could not be added, for the reason `errno` gives.
+    Apply {
+        path: String,
+        errno: c_int,
+    },
+    /// The files imported add up to more than allowed, counting up to this
+    /// one.
+    TooLarge {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixtureError::Io(err) => write!(f, "cannot read fixture: {}", err),
+            FixtureError::Parse { line, message } => {
+                write!(f, "fixture line {}: {}", line, message)
+            }
+            FixtureError::Apply { path, errno } => {
+                write!(f, "{}: {}", path, io::Error::from_raw_os_error(*errno))
+            }
+            FixtureError::TooLarge { path } => {
+                write!(f, "{}: tree too large to import", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for FixtureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FixtureError::Io(err) => Some(err),
+            FixtureError::Parse { .. }
+            | FixtureError::Apply { .. }
+            | FixtureError::TooLarge { .. } => None,
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+# the usual
+/etc/ mode=0755
+/etc/passwd "root:x:0:0\n\"quoted\"" owner=0:42
+/etc/empty   # nothing in it
+/etc/link -> passwd
+/etc/alias => /etc/passwd
+"#;
+        let fixture: Fixture = text.parse().unwrap();
+        let expected = Fixture::new()
+            .dir("/etc")
+            .mode(0o755)
+            .file("/etc/passwd", "root:x:0:0\n\"quoted\"")
+            .owner(0, 42)
+            .file("/etc/empty", "")
+            .symlink("/etc/link", "passwd")
+            .hard_link("/etc/alias", "/etc/passwd");
+        assert_eq!(fixture, expected);
+    }
+
+    #[test]
+    fn test_errors_point_at_line() {
+        for (text, line) in [
+            ("/a\n/b \"open", 2),
+            ("/a\nrelative", 2),
+            ("/a\n/b\n/c/ \"content\"", 3),
+            ("/a -> /b mode=0777", 1),
+            ("/a mode=0999", 1),
+            ("/a owner=alice", 1),
+            ("\n/a size=1", 2),
+            ("/a ->", 1),
+            ("/a\n/a/./b", 2),
+            ("/a\n/b\n/a/../b \"content\"", 3),
+            ("/a/.. -> b", 1),
+        ] {
+            let err = text.parse::<Fixture>().unwrap_err();
+            assert!(
+                matches!(err, FixtureError::Parse { line: l, .. } if l == line),
+                "{:?}: {}",
+                text,
+                err
+            );
+        }
+    }
+}
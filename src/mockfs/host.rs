@@ -0,0 +1,196 @@
+//! Trees moved between a [`MockFs`] and the real file system: one seen on a
+//! host read in to replay it against the mock, and a mock one written out
+//! to run the same scenario against libc.
+
+use super::fixture::{Fixture, FixtureError};
+use super::{parse_path, Ino, MockFs, Node, Tree, ROOT_INO};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs as unix_fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Reads a real directory tree into a [`Fixture`]: its directories, regular
+/// files and symlinks, with their modes and owners, and hard links between
+/// its files. Sockets, FIFOs and devices are left out. Contents and names
+/// that are not UTF-8 are read lossily.
+#[derive(Debug, Clone)]
+pub struct Import {
+    max_file_size: u64,
+    max_total_size: u64,
+}
+
+impl Import {
+    pub fn new() -> Self {
+        Import {
+            max_file_size: 1 << 20,
+            max_total_size: 64 << 20,
+        }
+    }
+
+    /// Cuts the content of larger files to `bytes`. 1 MiB by default.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Fails once the contents read add up to more than `bytes`. 64 MiB by
+    /// default.
+    pub fn max_total_size(mut self, bytes: u64) -> Self {
+        self.max_total_size = bytes;
+        self
+    }
+
+    /// The tree at `source`, to be put at `at` in the mock.
+    pub fn read(&self, source: impl AsRef<Path>, at: &str) -> Result<Fixture, FixtureError> {
+        let mut walk = Walk {
+            import: self,
+            fixture: Fixture::new(),
+            files: HashMap::new(),
+            total: 0,
+        };
+        walk.entry(source.as_ref(), at.to_string())?;
+        Ok(walk.fixture)
+    }
+}
+
+impl Default for Import {
+    fn default() -> Self {
+        Import::new()
+    }
+}
+
+struct Walk<'a> {
+    import: &'a Import,
+    fixture: Fixture,
+    /// Where each file with more than one name was first seen, by device
+    /// and inode.
+    files: HashMap<(u64, u64), String>,
+    total: u64,
+}
+
+impl Walk<'_> {
+    fn entry(&mut self, source: &Path, path: String) -> Result<(), FixtureError> {
+        let meta = fs::symlink_metadata(source).map_err(FixtureError::Io)?;
+        let (uid, gid) = (meta.uid(), meta.gid());
+        let file_type = meta.file_type();
+        if file_type.is_symlink() {
+            let target = fs::read_link(source).map_err(FixtureError::Io)?;
+            self.add(|fixture| {
+                fixture
+                    .symlink(&path, &target.to_string_lossy())
+                    .owner(uid, gid)
+            });
+            return Ok(());
+        } else if file_type.is_file() {
+            if meta.nlink() > 1 {
+                let key = (meta.dev(), meta.ino());
+                if let Some(first) = self.files.get(&key).cloned() {
+                    self.add(|fixture| fixture.hard_link(&path, &first));
+                    return Ok(());
+                }
+                self.files.insert(key, path.clone());
+            }
+            let size = meta.len().min(self.import.max_file_size);
+            self.total += size;
+            if self.total > self.import.max_total_size {
+                return Err(FixtureError::TooLarge {
+                    path: source.to_path_buf(),
+                });
+            }
+            let mut content = Vec::new();
+            fs::File::open(source)
+                .and_then(|file| file.take(size).read_to_end(&mut content))
+                .map_err(FixtureError::Io)?;
+            self.add(|fixture| fixture.file(&path, &String::from_utf8_lossy(&content)));
+        } else if file_type.is_dir() {
+            self.add(|fixture| fixture.dir(&path));
+        } else {
+            // sockets, FIFOs and devices have nothing like them in the mock
+            return Ok(());
+        }
+        self.add(|fixture| fixture.mode(meta.mode() & 0o7777).owner(uid, gid));
+
+        if file_type.is_dir() {
+            let mut names: Vec<_> = fs::read_dir(source)
+                .and_then(|entries| entries.map(|entry| Ok(entry?.file_name())).collect())
+                .map_err(FixtureError::Io)?;
+            names.sort();
+            for name in names {
+                let child = format!("{}/{}", path.trim_end_matches('/'), name.to_string_lossy());
+                self.entry(&source.join(name), child)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, f: impl FnOnce(Fixture) -> Fixture) {
+        self.fixture = f(std::mem::take(&mut self.fixture));
+    }
+}
+
+impl MockFs {
+    /// Writes what is in the directory `from` into the real directory `dir`,
+    /// which has to exist. Owners are kept only when running as root.
+    /// Symlinks keep their targets as they are, so absolute ones mean the
+    /// same only when resolved in `dir` as the root.
+    pub fn export(&self, from: &str, dir: impl AsRef<Path>) -> io::Result<()> {
+        let tree = self.inner.tree.read().unwrap();
+        let ino = parse_path(from)
+            .iter()
+            .try_fold(ROOT_INO, |dir, name| tree.entry(dir, name))
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let mut export = Export {
+            tree: &tree,
+            files: HashMap::new(),
+            chown: unsafe { libc::geteuid() } == 0,
+        };
+        export.children(ino, dir.as_ref())
+    }
+}
+
+struct Export<'a> {
+    tree: &'a Tree,
+    /// Where each file was written first, to link its other names to.
+    files: HashMap<Ino, PathBuf>,
+    chown: bool,
+}
+
+impl Export<'_> {
+    fn children(&mut self, dir: Ino, dest: &Path) -> io::Result<()> {
+        let entries = self
+            .tree
+            .entries(dir)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOTDIR))?;
+        let mut names: Vec<_> = entries.iter().collect();
+        names.sort();
+        for (name, &ino) in names {
+            let path = dest.join(name);
+            let inode = &self.tree.inodes[&ino];
+            match &inode.node {
+                Node::Directory(_) => {
+                    fs::create_dir(&path)?;
+                    self.children(ino, &path)?;
+                }
+                Node::Regular(_) if self.files.contains_key(&ino) => {
+                    fs::hard_link(&self.files[&ino], &path)?;
+                    continue;
+                }
+                Node::Regular(content) => {
+                    fs::write(&path, content)?;
+                    self.files.insert(ino, path.clone());
+                }
+                Node::Symlink(target) => unix_fs::symlink(target, &path)?,
+            }
+            if self.chown {
+                unix_fs::lchown(&path, Some(inode.meta.uid), Some(inode.meta.gid))?;
+            }
+            // after the children and the owner, which would clear set-id bits
+            if !matches!(inode.node, Node::Symlink(_)) {
+                fs::set_permissions(&path, fs::Permissions::from_mode(inode.meta.mode))?;
+            }
+        }
+        Ok(())
+    }
+}
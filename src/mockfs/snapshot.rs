@@ -0,0 +1,187 @@
+//! Copies of the tree of a [`MockFs`], to put back later or to compare, so
+//! a race test can tell what the other threads changed.
+
+use super::{Ino, MockFs, Node, Tree, ROOT_INO};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// The tree of a [`MockFs`] at one point in time. Descriptors, the working
+/// directory and the credentials are not part of it.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    tree: Tree,
+}
+
+/// What a path names in a [`Snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub ino: u64,
+    pub kind: EntryKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    Regular,
+    Directory,
+    /// A symlink and its target.
+    Symlink(String),
+}
+
+/// How a path differs between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Created {
+        path: String,
+        entry: Entry,
+    },
+    Removed {
+        path: String,
+        entry: Entry,
+    },
+    /// The path names another inode, or a symlink that points elsewhere.
+    Retargeted {
+        path: String,
+        from: Entry,
+        to: Entry,
+    },
+    /// The path names the same inode, whose content, mode or owner changed.
+    Modified {
+        path: String,
+        entry: Entry,
+    },
+}
+
+impl MockFs {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tree: self.inner.tree.read().unwrap().clone(),
+        }
+    }
+
+    /// Puts the tree back as it was at `snapshot`. Files opened since keep
+    /// working, as if they had been unlinked while open.
+    pub fn restore(&self, snapshot: &Snapshot) {
+        let mut tree = self.inner.tree.write().unwrap();
+        let mut restored = snapshot.tree.clone();
+        let open: HashSet<Ino> = self
+            .inner
+            .fds
+            .lock()
+            .unwrap()
+            .fds
+            .values()
+            .map(|entry| entry.file.ino)
+            .collect();
+        // inode numbers are never reused, so the same number is the same file
+        for ino in open {
+            if let (None, Some(inode)) = (restored.inodes.get(&ino), tree.inodes.get(&ino)) {
+                let mut inode = inode.clone();
+                inode.nlink = 0;
+                restored.inodes.insert(ino, inode);
+            }
+        }
+        restored.next_ino = restored.next_ino.max(tree.next_ino);
+        restored.clock = restored.clock.max(tree.clock);
+        *tree = restored;
+    }
+}
+
+impl Snapshot {
+    /// Every path in the tree and what it names, without following symlinks.
+    fn entries(&self) -> BTreeMap<String, Entry> {
+        let mut entries = BTreeMap::new();
+        self.collect("/".to_string(), ROOT_INO, &mut entries);
+        entries
+    }
+
+    fn collect(&self, path: String, ino: Ino, entries: &mut BTreeMap<String, Entry>) {
+        let kind = match self.tree.node(ino) {
+            Some(Node::Regular(_)) => EntryKind::Regular,
+            Some(Node::Directory(children)) => {
+                for (name, &child) in children {
+                    let child_path = if path == "/" {
+                        format!("/{}", name)
+                    } else {
+                        format!("{}/{}", path, name)
+                    };
+                    self.collect(child_path, child, entries);
+                }
+                EntryKind::Directory
+            }
+            Some(Node::Symlink(target)) => EntryKind::Symlink(target.clone()),
+            None => return,
+        };
+        entries.insert(path, Entry { ino, kind });
+    }
+
+    /// Whether inode `ino` has other content, mode or owner in `other`.
+    fn modified(&self, other: &Snapshot, ino: Ino) -> bool {
+        let (Some(a), Some(b)) = (self.tree.inodes.get(&ino), other.tree.inodes.get(&ino)) else {
+            return false;
+        };
+        let content_changed = match (&a.node, &b.node) {
+            (Node::Regular(a), Node::Regular(b)) => a != b,
+            _ => false,
+        };
+        content_changed
+            || (a.meta.mode, a.meta.uid, a.meta.gid) != (b.meta.mode, b.meta.uid, b.meta.gid)
+    }
+}
+
+/// What changed from `a` to `b`, path by path in order.
+pub fn diff(a: &Snapshot, b: &Snapshot) -> Vec<Change> {
+    let before = a.entries();
+    let mut after = b.entries();
+    let mut changes = Vec::new();
+    for (path, from) in before {
+        match after.remove(&path) {
+            None => changes.push(Change::Removed { path, entry: from }),
+            Some(to) if to != from => changes.push(Change::Retargeted { path, from, to }),
+            Some(to) if a.modified(b, to.ino) => changes.push(Change::Modified { path, entry: to }),
+            Some(_) => {}
+        }
+    }
+    changes.extend(
+        after
+            .into_iter()
+            .map(|(path, entry)| Change::Created { path, entry }),
+    );
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Created { path, .. }
+            | Change::Removed { path, .. }
+            | Change::Retargeted { path, .. }
+            | Change::Modified { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            EntryKind::Regular => write!(f, "file (inode {})", self.ino),
+            EntryKind::Directory => write!(f, "directory (inode {})", self.ino),
+            EntryKind::Symlink(target) => {
+                write!(f, "symlink to {} (inode {})", target, self.ino)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Created { path, entry } => write!(f, "created {}: {}", path, entry),
+            Change::Removed { path, entry } => write!(f, "removed {}: {}", path, entry),
+            Change::Retargeted { path, from, to } => {
+                write!(f, "retargeted {}: {} -> {}", path, from, to)
+            }
+            Change::Modified { path, entry } => write!(f, "modified {}: {}", path, entry),
+        }
+    }
+}
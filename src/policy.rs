@@ -0,0 +1,505 @@
+pub mod file;
+
+use crate::fd::FileId;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::raw::c_int;
+use std::sync::{Arc, RwLock};
+
+/// The role a path component plays in the walk when the policy sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentKind {
+    /// A component that is opened as a directory to continue the walk.
+    Directory,
+    /// A symlink about to be followed; the path is that of the link itself.
+    Symlink,
+    /// The last component, whose descriptor is handed to the caller.
+    Final,
+}
+
+/// What the resolver is about to do, as presented to a [`Policy`].
+#[derive(Debug, Clone, Copy)]
+pub struct Access<'a> {
+    /// Absolute path of the component, as seen through its parent's fd.
+    pub path: &'a str,
+    pub kind: ComponentKind,
+    /// The flags the caller passed to `safe_open`.
+    pub flags: c_int,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+    /// The policy has no opinion; the resolver treats this as allow.
+    Abstain,
+}
+
+/// An access policy consulted by the resolver before every component is opened.
+pub trait Policy: Send + Sync {
+    fn check(&self, access: &Access) -> Decision;
+
+    /// Names the rule behind a [`Decision::Deny`], for error reports.
+    fn explain(&self, _access: &Access) -> Option<String> {
+        None
+    }
+
+    /// Judges the file handed back to the caller by what it is rather than
+    /// what it is called, once it is open and [`Policy::check`] let its path
+    /// through. This is what catches a hard link to a protected file under
+    /// an allowed name.
+    fn check_file(&self, _access: &Access, _file: FileId) -> Decision {
+        Decision::Abstain
+    }
+
+    /// Names the rule behind a [`Decision::Deny`] from [`Policy::check_file`].
+    fn explain_file(&self, _access: &Access, _file: FileId) -> Option<String> {
+        None
+    }
+
+    /// Looks up again whatever the policy learnt from the file system when it
+    /// was built, such as which files are protected, with `of_path`.
+    /// [`Resolver::refresh_policy`](crate::Resolver::refresh_policy) calls it
+    /// with a lookup on the resolver's file system.
+    fn refresh(&self, _of_path: &dyn Fn(&str) -> io::Result<FileId>) {}
+}
+
+impl<P: Policy + ?Sized> Policy for Box<P> {
+    fn check(&self, access: &Access) -> Decision {
+        (**self).check(access)
+    }
+
+    fn explain(&self, access: &Access) -> Option<String> {
+        (**self).explain(access)
+    }
+
+    fn check_file(&self, access: &Access, file: FileId) -> Decision {
+        (**self).check_file(access, file)
+    }
+
+    fn explain_file(&self, access: &Access, file: FileId) -> Option<String> {
+        (**self).explain_file(access, file)
+    }
+
+    fn refresh(&self, of_path: &dyn Fn(&str) -> io::Result<FileId>) {
+        (**self).refresh(of_path)
+    }
+}
+
+impl<P: Policy + ?Sized> Policy for Arc<P> {
+    fn check(&self, access: &Access) -> Decision {
+        (**self).check(access)
+    }
+
+    fn explain(&self, access: &Access) -> Option<String> {
+        (**self).explain(access)
+    }
+
+    fn check_file(&self, access: &Access, file: FileId) -> Decision {
+        (**self).check_file(access, file)
+    }
+
+    fn explain_file(&self, access: &Access, file: FileId) -> Option<String> {
+        (**self).explain_file(access, file)
+    }
+
+    fn refresh(&self, of_path: &dyn Fn(&str) -> io::Result<FileId>) {
+        (**self).refresh(of_path)
+    }
+}
+
+/// Denies the listed paths, whatever role they play in the walk.
+#[derive(Debug, Clone, Default)]
+pub struct DenyList {
+    paths: HashSet<String>,
+}
+
+impl DenyList {
+    pub fn new<I, S>(paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        DenyList {
+            paths: paths.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Policy for DenyList {
+    fn check(&self, access: &Access) -> Decision {
+        if self.paths.contains(access.path) {
+            Decision::Deny
+        } else {
+            Decision::Abstain
+        }
+    }
+
+    fn explain(&self, access: &Access) -> Option<String> {
+        Some(format!("deny-list entry {}", access.path))
+    }
+}
+
+/// Denies the listed files under any name, hard links included.
+///
+/// The files are identified on the default file system when the policy is
+/// built, and again on [`Policy::refresh`], for instance after one of them
+/// was replaced or to find them on another file system. A path that does not
+/// exist protects nothing until a refresh finds it.
+#[derive(Debug, Default)]
+pub struct ProtectedFiles {
+    paths: Vec<String>,
+    files: RwLock<HashMap<FileId, String>>,
+}
+
+impl ProtectedFiles {
+    pub fn new<I, S>(paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let protected = ProtectedFiles {
+            paths: paths.into_iter().map(Into::into).collect(),
+            files: RwLock::default(),
+        };
+        protected.refresh(&FileId::of_path);
+        protected
+    }
+
+    /// The listed path that `file` was found at, if it is protected.
+    pub fn protects(&self, file: FileId) -> Option<String> {
+        self.files.read().unwrap().get(&file).cloned()
+    }
+}
+
+impl Policy for ProtectedFiles {
+    fn check(&self, _access: &Access) -> Decision {
+        Decision::Abstain
+    }
+
+    fn check_file(&self, _access: &Access, file: FileId) -> Decision {
+        match self.protects(file) {
+            Some(_) => Decision::Deny,
+            None => Decision::Abstain,
+        }
+    }
+
+    fn explain_file(&self, _access: &Access, file: FileId) -> Option<String> {
+        self.protects(file)
+            .map(|path| format!("same file as {}", path))
+    }
+
+    fn refresh(&self, of_path: &dyn Fn(&str) -> io::Result<FileId>) {
+        let files = self
+            .paths
+            .iter()
+            .filter_map(|path| Some((of_path(path).ok()?, path.clone())))
+            .collect();
+        *self.files.write().unwrap() = files;
+    }
+}
+
+/// Only lets the listed paths be opened. Directories and symlinks crossed on
+/// the way are not restricted.
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    paths: HashSet<String>,
+}
+
+impl AllowList {
+    pub fn new<I, S>(paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        AllowList {
+            paths: paths.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Policy for AllowList {
+    fn check(&self, access: &Access) -> Decision {
+        match access.kind {
+            ComponentKind::Final if self.paths.contains(access.path) => Decision::Allow,
+            ComponentKind::Final => Decision::Deny,
+            _ => Decision::Abstain,
+        }
+    }
+
+    fn explain(&self, _access: &Access) -> Option<String> {
+        Some("not in allow-list".to_string())
+    }
+}
+
+/// Allows or denies a directory and everything beneath it.
+#[derive(Debug, Clone)]
+pub struct Subtree {
+    root: String,
+    decision: Decision,
+}
+
+impl Subtree {
+    pub fn allow(root: &str) -> Self {
+        Subtree::new(root, Decision::Allow)
+    }
+
+    pub fn deny(root: &str) -> Self {
+        Subtree::new(root, Decision::Deny)
+    }
+
+    fn new(root: &str, decision: Decision) -> Self {
+        let root = root.trim_end_matches('/');
+        Subtree {
+            root: root.to_string(),
+            decision,
+        }
+    }
+
+    fn contains(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.root) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.root.is_empty(),
+            None => false,
+        }
+    }
+}
+
+impl Policy for Subtree {
+    fn check(&self, access: &Access) -> Decision {
+        if self.contains(access.path) {
+            self.decision
+        } else {
+            Decision::Abstain
+        }
+    }
+
+    fn explain(&self, _access: &Access) -> Option<String> {
+        Some(format!("subtree {}/", self.root))
+    }
+}
+
+/// Allows or denies paths matching a shell-style pattern.
+///
+/// `?` matches one character and `*` any run of characters within a single
+/// component; `**` also crosses `/`, and `/**/` matches zero or more
+/// directories.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    pattern: String,
+    decision: Decision,
+}
+
+impl Glob {
+    pub fn allow(pattern: &str) -> Self {
+        Glob {
+            pattern: pattern.to_string(),
+            decision: Decision::Allow,
+        }
+    }
+
+    pub fn deny(pattern: &str) -> Self {
+        Glob {
+            pattern: pattern.to_string(),
+            decision: Decision::Deny,
+        }
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn decision(&self) -> Decision {
+        self.decision
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        glob_match(self.pattern.as_bytes(), path.as_bytes())
+    }
+}
+
+impl Policy for Glob {
+    fn check(&self, access: &Access) -> Decision {
+        if self.matches(access.path) {
+            self.decision
+        } else {
+            Decision::Abstain
+        }
+    }
+
+    fn explain(&self, _access: &Access) -> Option<String> {
+        Some(format!("pattern {}", self.pattern))
+    }
+}
+
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // "/**/" may also stand for a single "/"
+            if let Some(after_slash) = rest.strip_prefix(b"/") {
+                if glob_match(after_slash, path) {
+                    return true;
+                }
+            }
+            (0..=path.len()).any(|i| glob_match(rest, &path[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let component_len = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..=component_len).any(|i| glob_match(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => match path {
+            [c, path_rest @ ..] if *c != b'/' => glob_match(rest, path_rest),
+            _ => false,
+        },
+        [c, rest @ ..] => match path {
+            [d, path_rest @ ..] if c == d => glob_match(rest, path_rest),
+            _ => false,
+        },
+    }
+}
+
+/// An ordered list of policies where the first one that does not abstain wins.
+#[derive(Default)]
+pub struct PolicySet {
+    policies: Vec<Box<dyn Policy>>,
+}
+
+impl PolicySet {
+    pub fn new() -> Self {
+        PolicySet::default()
+    }
+
+    pub fn with(mut self, policy: impl Policy + 'static) -> Self {
+        self.push(policy);
+        self
+    }
+
+    pub fn push(&mut self, policy: impl Policy + 'static) {
+        self.policies.push(Box::new(policy));
+    }
+}
+
+impl PolicySet {
+    fn deciding(&self, access: &Access) -> Option<(&dyn Policy, Decision)> {
+        self.policies
+            .iter()
+            .map(|policy| (&**policy, policy.check(access)))
+            .find(|(_, decision)| *decision != Decision::Abstain)
+    }
+
+    fn deciding_file(&self, access: &Access, file: FileId) -> Option<(&dyn Policy, Decision)> {
+        self.policies
+            .iter()
+            .map(|policy| (&**policy, policy.check_file(access, file)))
+            .find(|(_, decision)| *decision != Decision::Abstain)
+    }
+}
+
+impl Policy for PolicySet {
+    fn check(&self, access: &Access) -> Decision {
+        self.deciding(access)
+            .map_or(Decision::Abstain, |(_, decision)| decision)
+    }
+
+    fn explain(&self, access: &Access) -> Option<String> {
+        self.deciding(access)
+            .and_then(|(policy, _)| policy.explain(access))
+    }
+
+    fn check_file(&self, access: &Access, file: FileId) -> Decision {
+        self.deciding_file(access, file)
+            .map_or(Decision::Abstain, |(_, decision)| decision)
+    }
+
+    fn explain_file(&self, access: &Access, file: FileId) -> Option<String> {
+        self.deciding_file(access, file)
+            .and_then(|(policy, _)| policy.explain_file(access, file))
+    }
+
+    fn refresh(&self, of_path: &dyn Fn(&str) -> io::Result<FileId>) {
+        for policy in &self.policies {
+            policy.refresh(of_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(path: &str, kind: ComponentKind) -> Access<'_> {
+        Access {
+            path,
+            kind,
+            flags: libc::O_RDONLY,
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let glob = Glob::deny("/home/*/.ssh/**");
+        assert!(glob.matches("/home/alice/.ssh/id_rsa"));
+        assert!(glob.matches("/home/alice/.ssh/keys/id_rsa"));
+        assert!(!glob.matches("/home/alice/work/.ssh/id_rsa"));
+
+        assert!(Glob::deny("/etc/*.conf").matches("/etc/resolv.conf"));
+        assert!(!Glob::deny("/etc/*.conf").matches("/etc/ssh/sshd.conf"));
+        assert!(Glob::deny("/var/log/??.log").matches("/var/log/ab.log"));
+        assert!(Glob::deny("/srv/**/secret").matches("/srv/secret"));
+        assert!(Glob::deny("/srv/**/secret").matches("/srv/a/b/secret"));
+    }
+
+    #[test]
+    fn test_subtree() {
+        let subtree = Subtree::deny("/etc/");
+        assert_eq!(
+            subtree.check(&access("/etc", ComponentKind::Directory)),
+            Decision::Deny
+        );
+        assert_eq!(
+            subtree.check(&access("/etc/passwd", ComponentKind::Final)),
+            Decision::Deny
+        );
+        assert_eq!(
+            subtree.check(&access("/etcetera", ComponentKind::Final)),
+            Decision::Abstain
+        );
+        assert_eq!(
+            Subtree::deny("/").check(&access("/anything", ComponentKind::Final)),
+            Decision::Deny
+        );
+    }
+
+    #[test]
+    fn test_allow_list_only_restricts_final() {
+        let allow = AllowList::new(["/srv/data"]);
+        assert_eq!(
+            allow.check(&access("/srv", ComponentKind::Directory)),
+            Decision::Abstain
+        );
+        assert_eq!(
+            allow.check(&access("/srv/data", ComponentKind::Final)),
+            Decision::Allow
+        );
+        assert_eq!(
+            allow.check(&access("/srv/other", ComponentKind::Final)),
+            Decision::Deny
+        );
+    }
+
+    #[test]
+    fn test_policy_set_first_match_wins() {
+        let policy = PolicySet::new()
+            .with(Subtree::allow("/srv/public"))
+            .with(Subtree::deny("/srv"))
+            .with(DenyList::new(["/etc/shadow"]));
+        let check = |path| policy.check(&access(path, ComponentKind::Final));
+        assert_eq!(check("/srv/public/index.html"), Decision::Allow);
+        assert_eq!(check("/srv/private/key"), Decision::Deny);
+        assert_eq!(check("/etc/shadow"), Decision::Deny);
+        assert_eq!(check("/etc/hosts"), Decision::Abstain);
+
+        let denied = access("/srv/private/key", ComponentKind::Final);
+        assert_eq!(policy.explain(&denied).as_deref(), Some("subtree /srv/"));
+    }
+}
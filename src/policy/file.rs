@@ -0,0 +1,357 @@
+//! Policies loaded from a TOML file at startup.
+//!
+//! ```toml
+//! # decision when no rule matches; omit to allow
+//! default = "allow"
+//!
+//! [[rule]]
+//! allow = "/etc/**"
+//! access = "read"      # "read", "write" or "any" (the default)
+//!
+//! [[rule]]
+//! deny = "/etc/**"
+//! ```
+//!
+//! Rules are tried in order and the first one whose pattern matches the path
+//! being opened, and whose `access` covers the requested flags, decides.
+//! Patterns use the [`Glob`] syntax. Rules only judge the file handed back to
+//! the caller; directories and symlinks crossed on the way are left to the
+//! final check.
+//!
+//! A `deny` rule without wildcards also denies the file it names under any
+//! other name, such as a hard link. [`FilePolicy::load`] looks those files
+//! up on the default file system, and [`Policy::refresh`] looks them up
+//! again, as [`Resolver::refresh_policy`](crate::Resolver::refresh_policy)
+//! does on the resolver's.
+
+use super::{Access, ComponentKind, Decision, Glob, Policy};
+use crate::fd::FileId;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::RwLock;
+use toml::Spanned;
+
+/// Which kind of open a rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessMode {
+    /// Opens that cannot modify the file.
+    Read,
+    /// Opens for writing, appending, truncating or creating.
+    Write,
+    #[default]
+    Any,
+}
+
+impl AccessMode {
+    pub fn covers(self, flags: c_int) -> bool {
+        let writes = flags & libc::O_ACCMODE != libc::O_RDONLY
+            || flags & (libc::O_CREAT | libc::O_TRUNC | libc::O_APPEND) != 0;
+        match self {
+            AccessMode::Read => !writes,
+            AccessMode::Write => writes,
+            AccessMode::Any => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub glob: Glob,
+    pub access: AccessMode,
+    /// Line of the rule in the policy file, for diagnostics.
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct FilePolicy {
+    rules: Vec<Rule>,
+    default: Decision,
+    /// The files named by `deny` rules without wildcards, with the index of
+    /// the rule.
+    protected: RwLock<Vec<(FileId, usize)>>,
+}
+
+impl FilePolicy {
+    /// Reads and parses the policy file, then looks up the files its rules
+    /// protect.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PolicyFileError> {
+        let policy: FilePolicy = fs::read_to_string(path)
+            .map_err(PolicyFileError::Io)?
+            .parse()?;
+        policy.refresh(&FileId::of_path);
+        Ok(policy)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The first rule that applies to `access`, if any.
+    pub fn matching_rule(&self, access: &Access) -> Option<&Rule> {
+        if access.kind != ComponentKind::Final {
+            return None;
+        }
+        self.rules
+            .iter()
+            .find(|rule| rule.access.covers(access.flags) && rule.glob.matches(access.path))
+    }
+
+    /// The first `deny` rule that protects `file` from `access`, if any.
+    pub fn protecting_rule(&self, access: &Access, file: FileId) -> Option<&Rule> {
+        if access.kind != ComponentKind::Final {
+            return None;
+        }
+        let protected = self.protected.read().unwrap();
+        protected
+            .iter()
+            .map(|&(id, index)| (id, &self.rules[index]))
+            .find(|(id, rule)| *id == file && rule.access.covers(access.flags))
+            .map(|(_, rule)| rule)
+    }
+}
+
+impl Clone for FilePolicy {
+    fn clone(&self) -> Self {
+        FilePolicy {
+            rules: self.rules.clone(),
+            default: self.default,
+            protected: RwLock::new(self.protected.read().unwrap().clone()),
+        }
+    }
+}
+
+impl Policy for FilePolicy {
+    fn check(&self, access: &Access) -> Decision {
+        match self.matching_rule(access) {
+            Some(rule) => rule.glob.decision(),
+            None if access.kind == ComponentKind::Final => self.default,
+            None => Decision::Abstain,
+        }
+    }
+
+    fn explain(&self, access: &Access) -> Option<String> {
+        match self.matching_rule(access) {
+            Some(rule) => Some(format!("line {}: deny {}", rule.line, rule.glob.pattern())),
+            None => Some("default = \"deny\"".to_string()),
+        }
+    }
+
+    fn check_file(&self, access: &Access, file: FileId) -> Decision {
+        match self.protecting_rule(access, file) {
+            Some(_) => Decision::Deny,
+            None => Decision::Abstain,
+        }
+    }
+
+    fn explain_file(&self, access: &Access, file: FileId) -> Option<String> {
+        self.protecting_rule(access, file)
+            .map(|rule| format!("line {}: deny {}", rule.line, rule.glob.pattern()))
+    }
+
+    fn refresh(&self, of_path: &dyn Fn(&str) -> io::Result<FileId>) {
+        let protected = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| {
+                rule.glob.decision() == Decision::Deny && !rule.glob.pattern().contains(['*', '?'])
+            })
+            .filter_map(|(index, rule)| Some((of_path(rule.glob.pattern()).ok()?, index)))
+            .collect();
+        *self.protected.write().unwrap() = protected;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawDecision {
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPolicy {
+    default: Option<RawDecision>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<Spanned<RawRule>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    allow: Option<String>,
+    deny: Option<String>,
+    #[serde(default)]
+    access: AccessMode,
+}
+
+impl FromStr for FilePolicy {
+    type Err = PolicyFileError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let raw: RawPolicy = toml::from_str(text).map_err(|err| PolicyFileError::Parse {
+            line: err.span().map_or(1, |span| line_of(text, span.start)),
+            message: err.message().to_string(),
+        })?;
+
+        let mut rules = Vec::with_capacity(raw.rules.len());
+        for spanned in raw.rules {
+            let line = line_of(text, spanned.span().start);
+            let rule = spanned.into_inner();
+            let glob = match (rule.allow, rule.deny) {
+                (Some(pattern), None) => Glob::allow(&pattern),
+                (None, Some(pattern)) => Glob::deny(&pattern),
+                (allow, _) => {
+                    let message = if allow.is_some() {
+                        "rule has both `allow` and `deny`"
+                    } else {
+                        "rule needs either `allow` or `deny`"
+                    };
+                    return Err(PolicyFileError::Parse {
+                        line,
+                        message: message.to_string(),
+                    });
+                }
+            };
+            rules.push(Rule {
+                glob,
+                access: rule.access,
+                line,
+            });
+        }
+
+        let default = match raw.default {
+            Some(RawDecision::Allow) | None => Decision::Abstain,
+            Some(RawDecision::Deny) => Decision::Deny,
+        };
+        Ok(FilePolicy {
+            rules,
+            default,
+            protected: RwLock::default(),
+        })
+    }
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+#[derive(Debug)]
+pub enum PolicyFileError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for PolicyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyFileError::Io(err) => write!(f, "cannot read policy file: {}", err),
+            PolicyFileError::Parse { line, message } => {
+                write!(f, "policy file line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PolicyFileError::Io(err) => Some(err),
+            PolicyFileError::Parse { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+default = "deny"
+
+[[rule]]
+allow = "/etc/**"
+access = "read"
+
+[[rule]]
+deny = "/etc/**"
+
+[[rule]]
+allow = "/srv/**"
+"#;
+
+    fn check(policy: &FilePolicy, path: &str, flags: c_int) -> Decision {
+        policy.check(&Access {
+            path,
+            kind: ComponentKind::Final,
+            flags,
+        })
+    }
+
+    #[test]
+    fn test_rules_in_order() {
+        let policy: FilePolicy = POLICY.parse().unwrap();
+        assert_eq!(
+            check(&policy, "/etc/hosts", libc::O_RDONLY),
+            Decision::Allow
+        );
+        assert_eq!(check(&policy, "/etc/hosts", libc::O_WRONLY), Decision::Deny);
+        assert_eq!(check(&policy, "/srv/data", libc::O_RDWR), Decision::Allow);
+        assert_eq!(check(&policy, "/home/user", libc::O_RDONLY), Decision::Deny);
+
+        let directory = Access {
+            path: "/home",
+            kind: ComponentKind::Directory,
+            flags: libc::O_RDONLY,
+        };
+        assert_eq!(policy.check(&directory), Decision::Abstain);
+
+        let write = Access {
+            path: "/etc/hosts",
+            kind: ComponentKind::Final,
+            flags: libc::O_WRONLY,
+        };
+        assert_eq!(
+            policy.explain(&write).as_deref(),
+            Some("line 8: deny /etc/**")
+        );
+        assert_eq!(policy.matching_rule(&directory).map(|rule| rule.line), None);
+    }
+
+    #[test]
+    fn test_errors_point_at_line() {
+        let err = "[[rule]]\nallow = \"/a\"\n\n[[rule]]\naccess = \"read\"\n"
+            .parse::<FilePolicy>()
+            .unwrap_err();
+        assert!(
+            matches!(err, PolicyFileError::Parse { line: 4, .. }),
+            "{}",
+            err
+        );
+
+        let err = "[[rule]]\ndeny = \"/a\"\naccess = \"execute\"\n"
+            .parse::<FilePolicy>()
+            .unwrap_err();
+        assert!(
+            matches!(err, PolicyFileError::Parse { line: 3, .. }),
+            "{}",
+            err
+        );
+
+        let err = "default = \"allow\"\nbogus = 1\n"
+            .parse::<FilePolicy>()
+            .unwrap_err();
+        assert!(
+            matches!(err, PolicyFileError::Parse { line: 2, .. }),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,887 @@
+use crate::backend::{default_fs, DefaultFs, FileSystem};
+use crate::error::OpenError;
+use crate::fd::{FileId, SafeFd};
+use crate::policy::{Access, ComponentKind, Decision, DenyList, Policy};
+use crate::{CREDENTIALS, DELIM, MAX_PATH_SIZE};
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Upper bound on the components of a path or symlink target; a component
+/// takes at least two bytes of a `MAX_PATH_SIZE` path.
+pub const MAX_COMPONENTS: usize = MAX_PATH_SIZE / 2;
+
+/// How many symlinks a single walk follows by default, as Linux's MAXSYMLINKS.
+pub const MAX_SYMLINK_HOPS: usize = 40;
+
+/// How the directories of a walk are opened: for lookups only, which needs
+/// no more than search permission and never blocks on what turns out not
+/// to be a directory.
+const DIR_FLAGS: c_int = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+
+/// How `..` components are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DotDot {
+    /// `..` is the parent of the directory actually reached, as in the
+    /// kernel: after `link/..` the walk is in the parent of the link target.
+    #[default]
+    Physical,
+    /// `..` cancels the component before it in the path or symlink target as
+    /// written, like `cd -L`. A `..` with nothing left to cancel is resolved
+    /// physically.
+    Lexical,
+}
+
+/// Opens paths one component at a time, checking an access [`Policy`] on
+/// every component and every symlink target along the way.
+///
+/// No symlink is followed by the kernel: each link is read with `readlinkat`
+/// and its target is walked with `O_NOFOLLOW`, so swapping a component for a
+/// link to a protected file between the check and the open is caught. A hard
+/// link cannot be told apart by its path, so the file handed back is also
+/// checked by identity, with [`Policy::check_file`], once it is open.
+///
+/// Every call goes to the [`FileSystem`] `F`, the default one unless the
+/// resolver is made [`with_fs`](Resolver::with_fs).
+pub struct Resolver<F: FileSystem = DefaultFs> {
+    fs: F,
+    policy: Box<dyn Policy>,
+    max_symlink_hops: usize,
+    dot_dot: DotDot,
+    forbid_escape: bool,
+    /// Cleared when `openat2` turns out to be missing, so it is probed once.
+    openat2: AtomicBool,
+    debug_fds: bool,
+}
+
+/// What `/` means to a walk.
+#[derive(Clone, Copy)]
+enum Anchor {
+    /// The real root directory.
+    Root,
+    /// The starting directory is a ceiling: absolute paths, absolute symlink
+    /// targets and any `..` above it fail, like `RESOLVE_BENEATH`.
+    Beneath,
+    /// The directory stands in for `/`: absolute targets restart there and
+    /// `..` stops there, like `RESOLVE_IN_ROOT`.
+    InRoot(RawFd),
+}
+
+/// The state of one walk: the directory reached so far and what is left.
+struct Walk<F: FileSystem> {
+    /// Replacing it closes the directory the walk leaves.
+    fd: SafeFd<F>,
+    anchor: Anchor,
+    /// Components still to be opened; symlink targets are spliced in front.
+    pending: VecDeque<String>,
+    hops: usize,
+    flags: c_int,
+    /// How many directories below the starting one the walk is.
+    depth: usize,
+    /// The path ended in `/`, `/.` or `/..`, so the last component has to be
+    /// a directory.
+    wants_dir: bool,
+}
+
+/// Opens `pathname` with a [`Resolver`] on the default file system that
+/// denies only [`CREDENTIALS`].
+pub fn safe_open(pathname: &str, flags: c_int) -> Result<SafeFd, OpenError> {
+    safe_openat(libc::AT_FDCWD, pathname, flags)
+}
+
+/// Like [`safe_open`], but relative paths are resolved against the
+/// directory `dirfd` (or the working directory for `libc::AT_FDCWD`).
+pub fn safe_openat(dirfd: RawFd, pathname: &str, flags: c_int) -> Result<SafeFd, OpenError> {
+    Resolver::new(DenyList::new([CREDENTIALS])).safe_openat(dirfd, pathname, flags)
+}
+
+impl Resolver {
+    /// A resolver on the default file system: the real one, or under
+    /// `feature = "mock"` the [`MockFs`](crate::mockfs::MockFs) installed on
+    /// the calling thread.
+    pub fn new(policy: impl Policy + 'static) -> Self {
+        Resolver::with_fs(default_fs(), policy)
+    }
+}
+
+impl<F: FileSystem> Resolver<F> {
+    /// A resolver on `fs`. A policy that looked up the files it protects on
+    /// another file system needs a [`Resolver::refresh_policy`] first.
+    pub fn with_fs(fs: F, policy: impl Policy + 'static) -> Self {
+        Resolver {
+            fs,
+            policy: Box::new(policy),
+            max_symlink_hops: MAX_SYMLINK_HOPS,
+            dot_dot: DotDot::default(),
+            forbid_escape: false,
+            openat2: AtomicBool::new(true),
+            debug_fds: false,
+        }
+    }
+
+    /// Sets how many symlinks one `safe_open` may follow before failing with
+    /// [`OpenError::SymlinkLoop`].
+    pub fn max_symlink_hops(mut self, hops: usize) -> Self {
+        self.max_symlink_hops = hops;
+        self
+    }
+
+    pub fn dot_dot(mut self, dot_dot: DotDot) -> Self {
+        self.dot_dot = dot_dot;
+        self
+    }
+
+    /// Makes a `..` that would climb above the directory the walk started
+    /// from (`/` for absolute paths) fail with [`OpenError::EscapesRoot`].
+    /// An absolute symlink target still restarts the walk at `/`; use
+    /// [`Resolver::safe_open_beneath`] to reject those as well.
+    pub fn forbid_escape(mut self, forbid: bool) -> Self {
+        self.forbid_escape = forbid;
+        self
+    }
+
+    /// Whether to try opening paths without symlinks or `..` in a single
+    /// `openat2` call before walking them. On by default; kernels without
+    /// `openat2` are detected on first use.
+    pub fn use_openat2(self, enable: bool) -> Self {
+        self.openat2.store(enable, Ordering::Relaxed);
+        self
+    }
+
+    /// Reports on stderr every descriptor that a `safe_open` leaves open
+    /// besides the one it returns. Descriptors opened meanwhile by other
+    /// threads are reported too, so this is for debugging only.
+    pub fn debug_fds(mut self, enable: bool) -> Self {
+        self.debug_fds = enable;
+        self
+    }
+
+    pub fn policy(&self) -> &dyn Policy {
+        &*self.policy
+    }
+
+    /// Calls [`Policy::refresh`] with lookups on the resolver's file system,
+    /// for instance after a protected file was replaced. A policy shared with
+    /// resolvers on other file systems is refreshed for all of them.
+    pub fn refresh_policy(&self) {
+        self.policy
+            .refresh(&|path| FileId::of_path_in(&self.fs, path));
+    }
+
+    pub fn fs(&self) -> &F {
+        &self.fs
+    }
+
+    pub fn safe_open(&self, pathname: &str, flags: c_int) -> Result<SafeFd<F>, OpenError> {
+        self.safe_openat(libc::AT_FDCWD, pathname, flags)
+    }
+
+    /// Like [`Resolver::safe_open`], but relative paths are resolved against
+    /// the directory `dirfd` (or the working directory for `libc::AT_FDCWD`).
+    ///
+    /// `dirfd` is only borrowed; the walk operates on its own descriptors.
+    pub fn safe_openat(
+        &self,
+        dirfd: RawFd,
+        pathname: &str,
+        flags: c_int,
+    ) -> Result<SafeFd<F>, OpenError> {
+        self.start(Anchor::Root, dirfd, pathname, flags)
+    }
+
+    /// Opens `pathname` relative to `root_fd` without ever leaving it.
+    ///
+    /// Absolute paths, absolute symlink targets and any `..` that would
+    /// climb above `root_fd` fail with [`OpenError::EscapesRoot`]. The policy
+    /// still sees the real absolute path of every component.
+    pub fn safe_open_beneath(
+        &self,
+        root_fd: RawFd,
+        pathname: &str,
+        flags: c_int,
+    ) -> Result<SafeFd<F>, OpenError> {
+        self.start(Anchor::Beneath, root_fd, pathname, flags)
+    }
+
+    /// Opens `pathname` as if `root_fd` were `/`, as after a `chroot`.
+    ///
+    /// Absolute paths and absolute symlink targets are resolved from
+    /// `root_fd`, and `..` in `root_fd` stays there, so a tree of
+    /// user-supplied links can be served without escaping it.
+    pub fn safe_open_in_root(
+        &self,
+        root_fd: RawFd,
+        pathname: &str,
+        flags: c_int,
+    ) -> Result<SafeFd<F>, OpenError> {
+        self.start(Anchor::InRoot(root_fd), root_fd, pathname, flags)
+    }
+
+    fn start(
+        &self,
+        anchor: Anchor,
+        dirfd: RawFd,
+        pathname: &str,
+        flags: c_int,
+    ) -> Result<SafeFd<F>, OpenError> {
+        if !self.debug_fds {
+            return self.open_from(anchor, dirfd, pathname, flags);
+        }
+
+        let before = self.fs.open_fds();
+        let res = self.open_from(anchor, dirfd, pathname, flags);
+        let returned = res.as_ref().map(|fd| fd.as_raw_fd()).ok();
+        for fd in self.fs.open_fds() {
+            if !before.contains(&fd) && Some(fd) != returned {
+                let path = self
+                    .fd_path(fd)
+                    .map_or_else(|_| "?".to_string(), |path| display(&path));
+                eprintln!("safe_open({}): fd {} left open ({})", pathname, fd, path);
+            }
+        }
+        res
+    }
+
+    fn open_from(
+        &self,
+        anchor: Anchor,
+        dirfd: RawFd,
+        pathname: &str,
+        flags: c_int,
+    ) -> Result<SafeFd<F>, OpenError> {
+        let fd;
+        let mut path = pathname;
+
+        if path.is_empty() {
+            return Err(OpenError::NotFound {
+                path: String::new(),
+            });
+        }
+
+        if path.starts_with(DELIM) {
+            // event: absolute
+            fd = self.open_anchor(anchor, pathname)?;
+            // event: root_opened
+            path = &path[1..];
+            // event: made_relative
+        } else {
+            // event: not_absolute
+            fd = self.reopen(dirfd)?;
+            // event: cwd_opened
+        }
+
+        if let Some(opened) = self.open_fast(fd.as_raw_fd(), path, flags)? {
+            return Ok(opened);
+        }
+        self.resolve_from(anchor, fd, path, flags)
+    }
+
+    /// Opens `path` relative to `dirfd` with one `openat2` that refuses
+    /// symlinks, after checking the policy on every component as the walk
+    /// would. `None` leaves the path to the walk: `openat2` is missing, the
+    /// path has `..`, it crosses a symlink, or it failed in a way the walk
+    /// reports more precisely. A denial is left to the walk too, because
+    /// through a symlink it may visit other paths than the ones checked here.
+    fn open_fast(
+        &self,
+        dirfd: c_int,
+        path: &str,
+        flags: c_int,
+    ) -> Result<Option<SafeFd<F>>, OpenError> {
+        if !self.openat2.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let components: Vec<_> = path
+            .split(DELIM)
+            .filter(|&c| !c.is_empty() && c != ".")
+            .collect();
+        if components.contains(&"..") || components.len() > MAX_COMPONENTS {
+            return Ok(None);
+        }
+
+        let mut full_path = self.fd_path(dirfd)?;
+        for (i, component) in components.iter().enumerate() {
+            full_path.push(component);
+            let kind = if i + 1 == components.len() {
+                ComponentKind::Final
+            } else {
+                ComponentKind::Directory
+            };
+            if self.check(&full_path, kind, flags).is_err() {
+                return Ok(None);
+            }
+        }
+        if components.is_empty() && self.check(&full_path, ComponentKind::Final, flags).is_err() {
+            return Ok(None);
+        }
+
+        let wants_dir = components.is_empty() || path.ends_with(DELIM) || path.ends_with("/.");
+        let mut how: libc::open_how = unsafe { mem::zeroed() };
+        how.flags = (final_flags(flags) | libc::O_NOFOLLOW) as u64;
+        if wants_dir {
+            how.flags |= libc::O_DIRECTORY as u64;
+        }
+        how.mode = create_mode(flags) as u64;
+        how.resolve =
+            libc::RESOLVE_NO_SYMLINKS | libc::RESOLVE_NO_MAGICLINKS | libc::RESOLVE_BENEATH;
+
+        let path = if path.is_empty() { "." } else { path };
+        let fd = match self.fs.openat2(dirfd, &c_string(path)?, &how) {
+            Ok(fd) => unsafe { SafeFd::from_raw(fd, self.fs.clone()) },
+            Err(err) => {
+                if err.raw_os_error() == Some(libc::ENOSYS) {
+                    self.openat2.store(false, Ordering::Relaxed);
+                }
+                return Ok(None);
+            }
+        };
+        self.finish(&fd, &full_path, flags)?;
+        Ok(Some(fd))
+    }
+
+    fn resolve_from(
+        &self,
+        anchor: Anchor,
+        fd: SafeFd<F>,
+        path: &str,
+        flags: c_int,
+    ) -> Result<SafeFd<F>, OpenError> {
+        let mut walk = Walk {
+            fd,
+            anchor,
+            pending: VecDeque::new(),
+            hops: 0,
+            flags,
+            depth: 0,
+            wants_dir: false,
+        };
+        walk.push_front(path, self.dot_dot)?;
+        while let Some(component) = walk.pending.pop_front() {
+            self.process_component(&mut walk, &component)?;
+            // event: next_component
+        }
+        // event: fully_traversed
+        // assert: property.txt
+        Ok(walk.fd)
+    }
+
+    fn process_component(&self, walk: &mut Walk<F>, component: &str) -> Result<(), OpenError> {
+        match component {
+            // only left in the queue when it is the last component
+            "." => {
+                let dir_path = self.fd_path(walk.fd.as_raw_fd())?;
+                return self.open_component(walk, ".", &dir_path);
+            }
+            ".." => {
+                let dir_path = self.fd_path(walk.fd.as_raw_fd())?;
+                if walk.depth == 0 {
+                    match walk.anchor {
+                        // the root is its own parent
+                        Anchor::InRoot(_) if walk.pending.is_empty() => {
+                            return self.open_component(walk, ".", &dir_path);
+                        }
+                        Anchor::InRoot(_) => return Ok(()),
+                        Anchor::Beneath => {
+                            return Err(OpenError::EscapesRoot {
+                                path: display(&dir_path.join("..")),
+                            });
+                        }
+                        Anchor::Root if self.forbid_escape => {
+                            return Err(OpenError::EscapesRoot {
+                                path: display(&dir_path.join("..")),
+                            });
+                        }
+                        Anchor::Root => {}
+                    }
+                }
+                let parent = dir_path.parent().unwrap_or(&dir_path).to_path_buf();
+                self.open_component(walk, "..", &parent)?;
+                walk.depth = walk.depth.saturating_sub(1);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // a final symlink that the flags say not to follow is left to the
+        // open, which fails or, under `O_PATH`, hands back the link itself
+        let follow = !walk.pending.is_empty() || walk.wants_dir || follows_final(walk.flags);
+        let component_path = c_string(component)?;
+        let link = if follow {
+            self.fs
+                .readlinkat(walk.fd.as_raw_fd(), &component_path)
+                .ok()
+        } else {
+            None
+        };
+        if let Some(target) = link {
+            let link_path = self.fd_path(walk.fd.as_raw_fd())?.join(component);
+            walk.hops += 1;
+            if walk.hops > self.max_symlink_hops {
+                return Err(OpenError::SymlinkLoop {
+                    path: display(&link_path),
+                    limit: self.max_symlink_hops,
+                });
+            }
+            self.check(&link_path, ComponentKind::Symlink, walk.flags)?;
+            let target =
+                target
+                    .into_os_string()
+                    .into_string()
+                    .map_err(|_| OpenError::InvalidPath {
+                        path: display(&link_path),
+                    })?;
+
+            // if the content of the symlink is absolute, reset the fd and traverse
+            let target = match target.strip_prefix(DELIM) {
+                Some(relative) => {
+                    walk.fd = self.open_anchor(walk.anchor, &display(&link_path))?;
+                    walk.depth = 0;
+                    relative
+                }
+                None => &target,
+            };
+            return walk.push_front(target, self.dot_dot);
+        }
+
+        let full_path = self.fd_path(walk.fd.as_raw_fd())?.join(component);
+        self.open_component(walk, component, &full_path)?;
+        walk.depth += 1;
+        Ok(())
+    }
+
+    /// Checks the policy for `full_path` and opens it as `name` relative to
+    /// the walk's current directory, which it then replaces.
+    fn open_component(
+        &self,
+        walk: &mut Walk<F>,
+        name: &str,
+        full_path: &Path,
+    ) -> Result<(), OpenError> {
+        let is_final = walk.pending.is_empty();
+
+        // policy checking
+        let kind = if is_final {
+            ComponentKind::Final
+        } else {
+            ComponentKind::Directory
+        };
+        self.check(full_path, kind, walk.flags)?;
+
+        // only the final component is opened with the caller's flags
+        let component_flags = if !is_final {
+            DIR_FLAGS
+        } else if walk.wants_dir {
+            final_flags(walk.flags) | libc::O_NOFOLLOW | libc::O_DIRECTORY
+        } else {
+            final_flags(walk.flags) | libc::O_NOFOLLOW
+        };
+        let name = c_string(name)?;
+        let fd = self
+            .fs
+            .openat(
+                walk.fd.as_raw_fd(),
+                &name,
+                component_flags,
+                create_mode(component_flags),
+            )
+            .map_err(|err| OpenError::from_io(display(full_path), err))?;
+        // event: open_nonsym
+        walk.fd = unsafe { SafeFd::from_raw(fd, self.fs.clone()) };
+        if is_final {
+            self.finish(&walk.fd, full_path, walk.flags)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the policy on what the file handed back turned out to be, then
+    /// truncates it if `O_TRUNC` asked for that and [`final_flags`] held it
+    /// back, so that a refused file is left as it was.
+    fn finish(&self, fd: &SafeFd<F>, path: &Path, flags: c_int) -> Result<(), OpenError> {
+        let st = self
+            .fs
+            .fstat(fd.as_raw_fd())
+            .map_err(|err| OpenError::from_io(display(path), err))?;
+        let path = path.to_str().ok_or_else(|| OpenError::InvalidPath {
+            path: display(path),
+        })?;
+        let access = Access {
+            path,
+            kind: ComponentKind::Final,
+            flags,
+        };
+        let file = FileId::from(&st);
+        if self.policy.check_file(&access, file) == Decision::Deny {
+            return Err(OpenError::AccessDenied {
+                path: path.to_string(),
+                rule: self.policy.explain_file(&access, file),
+            });
+        }
+
+        let regular = st.st_mode & libc::S_IFMT == libc::S_IFREG;
+        if final_flags(flags) != flags && regular {
+            self.fs
+                .ftruncate(fd.as_raw_fd(), 0)
+                .map_err(|err| OpenError::from_io(path.to_string(), err))?;
+        }
+        Ok(())
+    }
+
+    fn check(&self, path: &Path, kind: ComponentKind, flags: c_int) -> Result<(), OpenError> {
+        let path = path.to_str().ok_or_else(|| OpenError::InvalidPath {
+            path: display(path),
+        })?;
+        let access = Access { path, kind, flags };
+        match self.policy.check(&access) {
+            Decision::Deny => Err(OpenError::AccessDenied {
+                path: path.to_string(),
+                rule: self.policy.explain(&access),
+            }),
+            Decision::Allow | Decision::Abstain => Ok(()),
+        }
+    }
+
+    /// Opens the directory an absolute `path` starts from.
+    fn open_anchor(&self, anchor: Anchor, path: &str) -> Result<SafeFd<F>, OpenError> {
+        match anchor {
+            Anchor::Root => self.open_dir(libc::AT_FDCWD, DELIM),
+            Anchor::Beneath => Err(OpenError::EscapesRoot {
+                path: path.to_string(),
+            }),
+            Anchor::InRoot(root_fd) => self.reopen(root_fd),
+        }
+    }
+
+    /// Opens a descriptor of our own for the directory `dirfd`.
+    fn reopen(&self, dirfd: RawFd) -> Result<SafeFd<F>, OpenError> {
+        self.open_dir(dirfd, ".")
+    }
+
+    fn open_dir(&self, dirfd: RawFd, path: &str) -> Result<SafeFd<F>, OpenError> {
+        let fd = self
+            .fs
+            .openat(dirfd, &c_string(path)?, DIR_FLAGS, 0)
+            .map_err(|err| OpenError::from_io(path.to_string(), err))?;
+        Ok(unsafe { SafeFd::from_raw(fd, self.fs.clone()) })
+    }
+
+    /// The path the kernel (or mockfs) currently associates with `fd`.
+    fn fd_path(&self, fd: c_int) -> Result<PathBuf, OpenError> {
+        let proc_path = format!("/proc/self/fd/{}", fd);
+        self.fs
+            .readlinkat(libc::AT_FDCWD, &c_string(&proc_path)?)
+            .map_err(|err| OpenError::Os {
+                path: proc_path,
+                errno: err.raw_os_error().unwrap_or(libc::EIO),
+            })
+    }
+}
+
+impl<F: FileSystem> Walk<F> {
+    /// Queues the components of `path` ahead of whatever is still pending.
+    ///
+    /// Empty and `.` components are dropped, except that a path with nothing
+    /// else left to open becomes a single `.` so that the directory reached
+    /// is reopened with the caller's flags.
+    fn push_front(&mut self, path: &str, dot_dot: DotDot) -> Result<(), OpenError> {
+        let mut components: Vec<_> = path
+            .split(DELIM)
+            .filter(|&c| !c.is_empty() && c != ".")
+            .collect();
+        if dot_dot == DotDot::Lexical {
+            components = lexical_clean(components);
+        }
+        if self.pending.len() + components.len() > MAX_COMPONENTS {
+            return Err(OpenError::TooManyComponents {
+                path: path.to_string(),
+                limit: MAX_COMPONENTS,
+            });
+        }
+
+        if self.pending.is_empty() {
+            let last = path.rsplit(DELIM).next();
+            self.wants_dir |= matches!(last, Some("") | Some(".") | Some(".."));
+            if components.is_empty() {
+                components.push(".");
+            }
+        }
+        for component in components.into_iter().rev() {
+            self.pending.push_front(component.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Cancels each `..` against the component before it.
+fn lexical_clean(components: Vec<&str>) -> Vec<&str> {
+    let mut cleaned: Vec<&str> = Vec::with_capacity(components.len());
+    for component in components {
+        match cleaned.last() {
+            Some(&previous) if component == ".." && previous != ".." => {
+                cleaned.pop();
+            }
+            _ => cleaned.push(component),
+        }
+    }
+    cleaned
+}
+
+fn c_string(component: &str) -> Result<CString, OpenError> {
+    CString::new(component).map_err(|_| OpenError::InvalidPath {
+        path: component.to_string(),
+    })
+}
+
+/// The flags to open the file handed back with: the caller's, less an
+/// `O_TRUNC` that [`Resolver::finish`] carries out once the file is known.
+fn final_flags(flags: c_int) -> c_int {
+    if flags & libc::O_ACCMODE != libc::O_RDONLY {
+        flags & !libc::O_TRUNC
+    } else {
+        flags
+    }
+}
+
+/// Whether an open with `flags` follows a final symlink: not with
+/// `O_NOFOLLOW`, and not with `O_CREAT | O_EXCL`, which fails on any name
+/// that exists.
+fn follows_final(flags: c_int) -> bool {
+    let excl = libc::O_CREAT | libc::O_EXCL;
+    flags & libc::O_NOFOLLOW == 0 && flags & excl != excl
+}
+
+/// The mode a file created with `flags` gets before the umask: 0666 when
+/// they create one, as `open_how` wants 0 otherwise.
+fn create_mode(flags: c_int) -> libc::mode_t {
+    if flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE {
+        0o666
+    } else {
+        0
+    }
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use super::*;
+    use crate::mockfs::snapshot::{self, Snapshot};
+    use crate::mockfs::{initialize_mockfs, MockFs};
+    use crate::policy::{DenyList, PolicySet, ProtectedFiles};
+    use crate::{CREDENTIALS, DIRECTORY, NONCREDENTIAL};
+    #[cfg(not(feature = "mock"))]
+    use libc::{link, mkdir, open, read, remove, renameat2, symlink};
+    use loom::thread;
+    #[cfg(not(feature = "mock"))]
+    use std::fs::read_link;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs as unix_fs;
+    use std::path::Path;
+
+    #[cfg(feature = "mock")]
+    use crate::mockfs::{link, mkdir, open, read, read_link, remove, renameat2, symlink};
+
+    /// The attacker: replaces the noncredential file with a symlink to the
+    /// credentials.
+    fn swap_in_symlink() {
+        unsafe {
+            remove(CString::new(NONCREDENTIAL).unwrap().as_ptr());
+            symlink(
+                CString::new(CREDENTIALS).unwrap().as_ptr(),
+                CString::new(NONCREDENTIAL).unwrap().as_ptr(),
+            );
+        }
+    }
+
+    /// The attacker: replaces the noncredential file with a hard link to the
+    /// credentials, the same file under the allowed name.
+    fn swap_in_hard_link() {
+        unsafe {
+            remove(CString::new(NONCREDENTIAL).unwrap().as_ptr());
+            link(
+                CString::new(CREDENTIALS).unwrap().as_ptr(),
+                CString::new(NONCREDENTIAL).unwrap().as_ptr(),
+            );
+        }
+    }
+
+    /// Where the attacker prepares a directory to swap in for the one the
+    /// files are in.
+    const EVIL: &str = "/home/cs_gakusei/work/rust_sandbox/evil";
+
+    /// The attacker: exchanges the directory of the files for one where the
+    /// noncredential file is a symlink back to the credentials, which by
+    /// then are in the attacker's directory.
+    fn swap_in_directory() {
+        let directory = CString::new(DIRECTORY.trim_end_matches('/')).unwrap();
+        let evil = CString::new(EVIL).unwrap();
+        unsafe {
+            renameat2(
+                libc::AT_FDCWD,
+                directory.as_ptr(),
+                libc::AT_FDCWD,
+                evil.as_ptr(),
+                libc::RENAME_EXCHANGE,
+            );
+        }
+    }
+
+    /// Prints what the other threads changed since `before`, to tell what led
+    /// to a failing interleaving.
+    fn print_changes(before: &Snapshot) {
+        for change in snapshot::diff(before, &MockFs::current().snapshot()) {
+            println!("changed: {}", change);
+        }
+    }
+
+    #[test]
+    fn test_safe_open() {
+        // so that the noncredential file is not a symlink at first
+        // fs::remove_file(NONCREDENTIAL);
+        // fs::File::create(NONCREDENTIAL);
+        // create_symlink(NONCREDENTIAL, SYMLINK);
+
+        loom::model(|| {
+            // replace the link so it points to another file denied to access
+            initialize_mockfs();
+            let t1 = thread::spawn(|| {
+                // make sure that it does not allow the access to the newly-pointed file
+                let resolver = Resolver::new(DenyList::new([CREDENTIALS]));
+                let res = resolver.safe_open(NONCREDENTIAL, libc::O_RDONLY);
+                if let Ok(fd) = res {
+                    let fd_path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+                    let pointed_path = read_link(&fd_path).unwrap().to_string_lossy().into_owned();
+                    assert_eq!(pointed_path, NONCREDENTIAL);
+                    // nor are the bytes behind it the credentials
+                    let mut content = [0u8; 64];
+                    let length =
+                        unsafe { read(fd.as_raw_fd(), content.as_mut_ptr().cast(), content.len()) };
+                    assert_ne!(&content[..length.max(0) as usize], b"credentials content");
+                } else {
+                    // println!("{}", res.unwrap_err());
+                    println!("open error");
+                }
+            });
+            let t2 = thread::spawn(swap_in_symlink);
+            t1.join().unwrap();
+            t2.join().unwrap();
+        })
+    }
+
+    #[test]
+    fn test_unsafe_open() {
+        // so that the noncredential file is not a symlink at first
+        // fs::remove_file(NONCREDENTIAL);
+        // fs::File::create(NONCREDENTIAL);
+        // create_symlink(NONCREDENTIAL, SYMLINK);
+
+        loom::model(|| {
+            initialize_mockfs();
+            let before = MockFs::current().snapshot();
+            let t1 = thread::spawn(move || {
+                // a regular file is opened as is, a symlink is checked by its target
+                let target = match read_link(NONCREDENTIAL) {
+                    Ok(t) => t,
+                    Err(_) => PathBuf::from(NONCREDENTIAL),
+                };
+                let target = target.to_str().unwrap();
+                println!("target: {:?}", target);
+                if target != CREDENTIALS {
+                    let fd =
+                        unsafe { open(CString::new(target).unwrap().as_ptr(), libc::O_RDONLY) };
+                    if fd == -1 {
+                        println!("open failed");
+                        return;
+                    }
+                    let fd_path = format!("/proc/self/fd/{}", fd);
+                    println!("unsafe_open: {}", fd_path);
+                    let pointed_path = read_link(&fd_path).unwrap().to_string_lossy().into_owned();
+                    println!("pointed_path: {}", pointed_path);
+                    if pointed_path == CREDENTIALS {
+                        print_changes(&before);
+                    }
+                    assert_ne!(pointed_path, CREDENTIALS.to_string());
+                } else {
+                    println!("access denied");
+                }
+            });
+            let t2 = thread::spawn(swap_in_symlink);
+            t1.join().unwrap();
+            t2.join().unwrap();
+        })
+    }
+
+    /// A hard link is the credentials file itself under a name the deny-list
+    /// allows, so only the check on the identity of the opened file stops it.
+    #[test]
+    fn test_hard_link_open() {
+        loom::model(|| {
+            initialize_mockfs();
+            let t1 = thread::spawn(|| {
+                let policy = PolicySet::new()
+                    .with(DenyList::new([CREDENTIALS]))
+                    .with(ProtectedFiles::new([CREDENTIALS]));
+                let resolver = Resolver::new(policy);
+                if let Ok(fd) = resolver.safe_open(NONCREDENTIAL, libc::O_RDONLY) {
+                    let mut content = [0u8; 64];
+                    let length =
+                        unsafe { read(fd.as_raw_fd(), content.as_mut_ptr().cast(), content.len()) };
+                    assert_ne!(&content[..length.max(0) as usize], b"credentials content");
+                }
+            });
+            let t2 = thread::spawn(swap_in_hard_link);
+            t1.join().unwrap();
+            t2.join().unwrap();
+        })
+    }
+
+    /// The directory swap renames the credentials, so that the symlink to
+    /// them is allowed by name; the identity check still refuses them.
+    #[test]
+    fn test_directory_swap() {
+        loom::model(|| {
+            initialize_mockfs();
+            unsafe {
+                mkdir(CString::new(EVIL).unwrap().as_ptr(), 0o755);
+                symlink(
+                    CString::new("../evil/credentials").unwrap().as_ptr(),
+                    CString::new(format!("{}/noncredential", EVIL))
+                        .unwrap()
+                        .as_ptr(),
+                );
+            }
+            // the protected files are looked up at startup, before the attack
+            let policy = PolicySet::new()
+                .with(DenyList::new([CREDENTIALS]))
+                .with(ProtectedFiles::new([CREDENTIALS]));
+            let resolver = Resolver::new(policy);
+            let t1 = thread::spawn(move || {
+                if let Ok(fd) = resolver.safe_open(NONCREDENTIAL, libc::O_RDONLY) {
+                    let mut content = [0u8; 64];
+                    let length =
+                        unsafe { read(fd.as_raw_fd(), content.as_mut_ptr().cast(), content.len()) };
+                    assert_ne!(&content[..length.max(0) as usize], b"credentials content");
+                }
+            });
+            let t2 = thread::spawn(swap_in_directory);
+            t1.join().unwrap();
+            t2.join().unwrap();
+        })
+    }
+
+    #[allow(dead_code)]
+    fn create_symlink(original_path: &str, link_path: &str) -> std::io::Result<()> {
+        let original = Path::new(original_path);
+        let link = Path::new(link_path);
+        if link.exists() || link.symlink_metadata().is_ok() {
+            std::fs::remove_file(link)?;
+        }
+
+        unix_fs::symlink(original, link)
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg(loom)]
+
 use loom::sync::atomic::AtomicUsize;
 use loom::thread;
 
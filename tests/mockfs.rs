@@ -0,0 +1,1408 @@
+#![cfg(all(feature = "mock", not(loom)))]
+
+use rust_sandbox::fd::open_fds;
+use rust_sandbox::mockfs::fixture::{Fixture, FixtureError};
+use rust_sandbox::mockfs::host::Import;
+use rust_sandbox::mockfs::snapshot::{self, Change, Entry, EntryKind};
+use rust_sandbox::mockfs::{self, FileType, MockFs};
+use rust_sandbox::policy::{DenyList, PolicySet, ProtectedFiles};
+use rust_sandbox::{FileSystem, OpenError, Resolver};
+use std::ffi::CString;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+
+fn create(path: &str, file_type: FileType) {
+    mockfs::create(path, file_type).unwrap();
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("rust_sandbox-mock-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn open_dir(path: &str) -> i32 {
+    let fd = unsafe { mockfs::open(CString::new(path).unwrap().as_ptr(), libc::O_RDONLY) };
+    assert_ne!(fd, -1, "{}", path);
+    fd
+}
+
+fn opened_path(res: Result<rust_sandbox::SafeFd, OpenError>) -> PathBuf {
+    let fd = res.unwrap().into_raw();
+    mockfs::read_link(format!("/proc/self/fd/{}", fd)).unwrap()
+}
+
+#[test]
+fn test_beneath_and_in_root() {
+    let _fs = MockFs::new().install();
+    create("/jail/etc/passwd", FileType::Regular("inside".to_string()));
+    create(
+        "/jail/absolute",
+        FileType::Symlink("/etc/passwd".to_string()),
+    );
+    create(
+        "/jail/etc/up",
+        FileType::Symlink("../../etc/passwd".to_string()),
+    );
+    create("/etc/passwd", FileType::Regular("outside".to_string()));
+    let root = open_dir("/jail");
+    let resolver = Resolver::new(PolicySet::new());
+
+    for path in ["/etc/passwd", "absolute", "etc/up", ".."] {
+        let res = resolver.safe_open_beneath(root, path, libc::O_RDONLY);
+        assert!(
+            matches!(res, Err(OpenError::EscapesRoot { .. })),
+            "{}: {:?}",
+            path,
+            res
+        );
+    }
+    assert_eq!(
+        opened_path(resolver.safe_open_beneath(root, "etc/../etc/passwd", libc::O_RDONLY)),
+        PathBuf::from("/jail/etc/passwd")
+    );
+
+    for path in ["/etc/passwd", "absolute", "etc/up", "../../etc/passwd"] {
+        assert_eq!(
+            opened_path(resolver.safe_open_in_root(root, path, libc::O_RDONLY)),
+            PathBuf::from("/jail/etc/passwd"),
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        opened_path(resolver.safe_open_in_root(root, "..", libc::O_RDONLY)),
+        PathBuf::from("/jail")
+    );
+}
+
+#[test]
+fn test_openat2_probe() {
+    let _fs = MockFs::new().install();
+    create("/probe/dir/file", FileType::Regular(String::new()));
+    create("/probe/link", FileType::Symlink("dir".to_string()));
+
+    // a new MockFs has no openat2
+    let resolver = Resolver::new(PolicySet::new());
+    let calls = mockfs::openat2_calls();
+    for _ in 0..3 {
+        assert_eq!(
+            opened_path(resolver.safe_open("/probe/dir/file", libc::O_RDONLY)),
+            PathBuf::from("/probe/dir/file")
+        );
+    }
+    // ENOSYS is remembered after the first attempt
+    assert_eq!(mockfs::openat2_calls(), calls + 1);
+
+    mockfs::set_openat2_supported(true);
+    let resolver = Resolver::new(PolicySet::new());
+    for path in [
+        "/probe/dir/file",
+        "/probe/link/file",
+        "/probe/link/../dir/file",
+    ] {
+        assert_eq!(
+            opened_path(resolver.safe_open(path, libc::O_RDONLY)),
+            PathBuf::from("/probe/dir/file"),
+            "{}",
+            path
+        );
+    }
+    // `..` goes straight to the walk; the link is refused by openat2 first
+    assert_eq!(mockfs::openat2_calls(), calls + 3);
+}
+
+/// `O_CREAT` and `O_TRUNC` change the tree through `openat2` as through
+/// `openat`, so the fast path needs no walk to create or truncate.
+#[test]
+fn test_openat2_create() {
+    let _fs = MockFs::new().install();
+    mockfs::set_openat2_supported(true);
+    create("/fast/file", FileType::Regular("content".to_string()));
+    let fs = MockFs::current();
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.resolve = libc::RESOLVE_NO_SYMLINKS;
+
+    how.flags = (libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL) as u64;
+    how.mode = 0o640;
+    let fd = fs.openat2(libc::AT_FDCWD, c"/fast/new", &how).unwrap();
+    fs.close(fd).unwrap();
+    assert_eq!(fs.stat(c"/fast/new").unwrap().st_mode & 0o7777, 0o640);
+    let err = fs.openat2(libc::AT_FDCWD, c"/fast/new", &how).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+
+    how.flags = (libc::O_WRONLY | libc::O_TRUNC) as u64;
+    how.mode = 0;
+    let fd = fs.openat2(libc::AT_FDCWD, c"/fast/file", &how).unwrap();
+    fs.close(fd).unwrap();
+    assert_eq!(fs.stat(c"/fast/file").unwrap().st_size, 0);
+
+    let resolver = Resolver::new(PolicySet::new());
+    let calls = mockfs::openat2_calls();
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+    assert_eq!(
+        opened_path(resolver.safe_open("/fast/made", flags)),
+        PathBuf::from("/fast/made")
+    );
+    assert_eq!(mockfs::openat2_calls(), calls + 1);
+    assert_eq!(fs.stat(c"/fast/made").unwrap().st_mode & 0o7777, 0o644);
+}
+
+#[test]
+fn test_no_fd_leaks() {
+    let _fs = MockFs::new().install();
+    create("/leaks/dir/file", FileType::Regular(String::new()));
+    create("/leaks/relative", FileType::Symlink("dir/file".to_string()));
+    create(
+        "/leaks/absolute",
+        FileType::Symlink("/leaks/dir".to_string()),
+    );
+    let resolver = Resolver::new(DenyList::new(["/leaks/dir/denied"])).debug_fds(true);
+
+    for supported in [false, true] {
+        mockfs::set_openat2_supported(supported);
+        for path in [
+            "/leaks/dir/file",
+            "/leaks/relative",
+            "/leaks/absolute/file",
+            "/leaks/dir/../dir/file",
+            "/",
+        ] {
+            let before = open_fds();
+            let fd = resolver.safe_open(path, libc::O_RDONLY).unwrap();
+            let mut expected = before.clone();
+            expected.push(fd.as_raw_fd());
+            assert_eq!(open_fds(), expected, "{}", path);
+            drop(fd);
+            assert_eq!(open_fds(), before, "{}", path);
+        }
+        for path in [
+            "/leaks/missing",
+            "/leaks/dir/file/child",
+            "/leaks/absolute/denied",
+        ] {
+            let before = open_fds();
+            assert!(resolver.safe_open(path, libc::O_RDONLY).is_err());
+            assert_eq!(open_fds(), before, "{}", path);
+        }
+    }
+}
+
+#[test]
+fn test_fd_table() {
+    let _fs = MockFs::new().install();
+    create("/fds/a", FileType::Regular(String::new()));
+    create("/fds/b", FileType::Regular(String::new()));
+    let proc_path = |fd: i32| mockfs::read_link(format!("/proc/self/fd/{}", fd));
+
+    let a = open_dir("/fds/a");
+    let b = open_dir("/fds/b");
+    assert_eq!(proc_path(b).unwrap(), PathBuf::from("/fds/b"));
+
+    // the lowest free number is reused and the /proc entry goes with it
+    unsafe {
+        assert_eq!(mockfs::close(a), 0);
+        assert_eq!(mockfs::close(a), -1);
+        assert!(proc_path(a).is_err());
+        assert_eq!(mockfs::dup(b), a);
+        assert_eq!(proc_path(a).unwrap(), PathBuf::from("/fds/b"));
+
+        assert_eq!(mockfs::dup2(b, 100), 100);
+        assert_eq!(proc_path(100).unwrap(), PathBuf::from("/fds/b"));
+        assert_eq!(mockfs::dup2(a, 100), 100);
+        assert_eq!(mockfs::dup2(b, b), b);
+        assert_eq!(mockfs::dup2(1000, 101), -1);
+
+        let c = mockfs::fcntl(b, libc::F_DUPFD_CLOEXEC, 50);
+        assert_eq!(c, 50);
+        assert_eq!(mockfs::fcntl(c, libc::F_GETFD, 0), libc::FD_CLOEXEC);
+        assert_eq!(mockfs::fcntl(b, libc::F_GETFD, 0), 0);
+        assert_eq!(mockfs::fcntl(c, libc::F_SETFD, 0), 0);
+        assert_eq!(mockfs::fcntl(c, libc::F_GETFD, 0), 0);
+
+        for fd in [a, b, c, 100] {
+            assert_eq!(mockfs::close(fd), 0);
+        }
+    }
+
+    let before = open_fds();
+    mockfs::set_fd_limit(before.len() + 1);
+    let last = open_dir("/fds/a");
+    let path = CString::new("/fds/b").unwrap();
+    assert_eq!(unsafe { mockfs::open(path.as_ptr(), libc::O_RDONLY) }, -1);
+    assert_eq!(mockfs::errno(), libc::EMFILE);
+    assert_eq!(unsafe { mockfs::dup(last) }, -1);
+    mockfs::set_fd_limit(1024);
+    unsafe { mockfs::close(last) };
+    assert_eq!(open_fds(), before);
+}
+
+#[test]
+fn test_read_write() {
+    let _fs = MockFs::new().install();
+    create("/io/file", FileType::Regular("hello".to_string()));
+    let path = CString::new("/io/file").unwrap();
+    let mut buf = [0u8; 16];
+
+    unsafe {
+        let fd = mockfs::open(path.as_ptr(), libc::O_RDWR);
+        let read = |fd: i32, buf: &mut [u8]| mockfs::read(fd, buf.as_mut_ptr().cast(), buf.len());
+        assert_eq!(read(fd, &mut buf[..3]), 3);
+        assert_eq!(&buf[..3], b"hel");
+
+        // duplicates share the offset
+        let dup = mockfs::dup(fd);
+        assert_eq!(read(dup, &mut buf), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(read(fd, &mut buf), 0);
+
+        assert_eq!(mockfs::write(fd, b" world".as_ptr().cast(), 6), 6);
+        assert_eq!(mockfs::lseek(fd, 0, libc::SEEK_CUR), 11);
+        assert_eq!(mockfs::pread(fd, buf.as_mut_ptr().cast(), 16, 6), 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(mockfs::lseek(fd, 0, libc::SEEK_CUR), 11);
+
+        assert_eq!(mockfs::pwrite(fd, b"J".as_ptr().cast(), 1, 0), 1);
+        assert_eq!(mockfs::lseek(fd, -5, libc::SEEK_END), 6);
+        assert_eq!(mockfs::lseek(fd, -7, libc::SEEK_CUR), -1);
+        assert_eq!(mockfs::ftruncate(fd, 5), 0);
+        assert_eq!(mockfs::lseek(fd, 0, libc::SEEK_SET), 0);
+        assert_eq!(read(fd, &mut buf), 5);
+        assert_eq!(&buf[..5], b"Jello");
+
+        // a gap left by seeking past the end reads back as NULs
+        assert_eq!(mockfs::lseek(fd, 7, libc::SEEK_SET), 7);
+        assert_eq!(mockfs::write(fd, b"!".as_ptr().cast(), 1), 1);
+        assert_eq!(mockfs::pread(fd, buf.as_mut_ptr().cast(), 16, 0), 8);
+        assert_eq!(&buf[..8], b"Jello\0\0!");
+        mockfs::close(dup);
+        mockfs::close(fd);
+
+        let fd = mockfs::open(path.as_ptr(), libc::O_RDONLY);
+        assert_eq!(mockfs::write(fd, b"x".as_ptr().cast(), 1), -1);
+        assert_eq!(mockfs::errno(), libc::EBADF);
+        assert_eq!(mockfs::ftruncate(fd, 0), -1);
+        assert_eq!(mockfs::errno(), libc::EINVAL);
+        mockfs::close(fd);
+        assert_eq!(mockfs::ftruncate(fd, 0), -1);
+        assert_eq!(mockfs::errno(), libc::EBADF);
+        let opath = mockfs::open(path.as_ptr(), libc::O_PATH);
+        assert_eq!(mockfs::ftruncate(opath, 0), -1);
+        assert_eq!(mockfs::errno(), libc::EBADF);
+        mockfs::close(opath);
+
+        let fd = mockfs::open(path.as_ptr(), libc::O_WRONLY | libc::O_APPEND);
+        assert_eq!(read(fd, &mut buf), -1);
+        assert_eq!(mockfs::pwrite(fd, b"?".as_ptr().cast(), 1, 0), 1);
+        mockfs::close(fd);
+
+        let fd = open_dir("/io");
+        assert_eq!(read(fd, &mut buf), -1);
+        assert_eq!(mockfs::errno(), libc::EISDIR);
+        mockfs::close(fd);
+    }
+
+    let fd = Resolver::new(PolicySet::new())
+        .safe_open("/io/file", libc::O_RDONLY)
+        .unwrap();
+    let length = unsafe { mockfs::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), 16) };
+    assert_eq!(&buf[..length as usize], b"Jello\0\0!?");
+}
+
+#[test]
+fn test_open_flags() {
+    let _fs = MockFs::new().install();
+    create("/flags/file", FileType::Regular("content".to_string()));
+    create("/flags/link", FileType::Symlink("file".to_string()));
+    create("/flags/dangling", FileType::Symlink("created".to_string()));
+    create("/flags/dir/keep", FileType::Regular(String::new()));
+    let open = |path: &str, flags: i32| {
+        let path = CString::new(path).unwrap();
+        let fd = unsafe { mockfs::open(path.as_ptr(), flags) };
+        let errno = mockfs::errno();
+        if fd == -1 {
+            Err(errno)
+        } else {
+            Ok(fd)
+        }
+    };
+    let content = |path: &str| {
+        let fd = open(path, libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 64];
+        let length = unsafe { mockfs::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        unsafe { mockfs::close(fd) };
+        String::from_utf8(buf[..length as usize].to_vec()).unwrap()
+    };
+
+    // O_NOFOLLOW is a bit, not the whole flags value
+    let nofollow = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    assert_eq!(open("/flags/link", nofollow), Err(libc::ELOOP));
+    let fd = open("/flags/link", libc::O_PATH | libc::O_NOFOLLOW).unwrap();
+    assert_eq!(
+        mockfs::read_link(format!("/proc/self/fd/{}", fd)).unwrap(),
+        PathBuf::from("/flags/link")
+    );
+    let mut buf = [0u8; 8];
+    assert_eq!(
+        unsafe { mockfs::read(fd, buf.as_mut_ptr().cast(), buf.len()) },
+        -1
+    );
+
+    assert_eq!(open("/flags/new", libc::O_RDONLY), Err(libc::ENOENT));
+    assert!(open("/flags/new", libc::O_WRONLY | libc::O_CREAT).is_ok());
+    assert_eq!(content("/flags/new"), "");
+    let exclusive = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+    assert_eq!(open("/flags/new", exclusive), Err(libc::EEXIST));
+    assert_eq!(open("/flags/dangling", exclusive), Err(libc::EEXIST));
+    assert!(open("/flags/dangling", libc::O_WRONLY | libc::O_CREAT).is_ok());
+    assert_eq!(content("/flags/created"), "");
+    let fd = unsafe {
+        let path = CString::new("/flags/private").unwrap();
+        mockfs::openat(libc::AT_FDCWD, path.as_ptr(), exclusive, 0o4640)
+    };
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { mockfs::fstat(fd, &mut st) }, 0);
+    assert_eq!(st.st_mode, libc::S_IFREG | 0o4640);
+    let fd = unsafe {
+        let path = CString::new("/flags/masked").unwrap();
+        mockfs::openat(libc::AT_FDCWD, path.as_ptr(), exclusive, 0o777)
+    };
+    assert_eq!(unsafe { mockfs::fstat(fd, &mut st) }, 0);
+    assert_eq!(st.st_mode, libc::S_IFREG | 0o755);
+    assert_eq!(open("/flags/missing/new", exclusive), Err(libc::ENOENT));
+    assert_eq!(open("/flags/file/new", exclusive), Err(libc::ENOTDIR));
+
+    let fd = open("/flags/file", libc::O_WRONLY | libc::O_APPEND).unwrap();
+    assert_eq!(unsafe { mockfs::write(fd, b"!".as_ptr().cast(), 1) }, 1);
+    assert_eq!(content("/flags/file"), "content!");
+    assert!(open("/flags/link", libc::O_WRONLY | libc::O_TRUNC).is_ok());
+    assert_eq!(content("/flags/file"), "");
+
+    assert_eq!(
+        open("/flags/file", libc::O_RDONLY | libc::O_DIRECTORY),
+        Err(libc::ENOTDIR)
+    );
+    assert_eq!(open("/flags/file/", libc::O_RDONLY), Err(libc::ENOTDIR));
+    assert!(open("/flags/dir/", libc::O_RDONLY | libc::O_DIRECTORY).is_ok());
+    assert_eq!(open("/flags/dir", libc::O_WRONLY), Err(libc::EISDIR));
+    assert_eq!(
+        open("/flags/dir", libc::O_RDONLY | libc::O_CREAT),
+        Err(libc::EISDIR)
+    );
+    assert!(open("/flags/dir", libc::O_PATH | libc::O_WRONLY).is_ok());
+
+    // the resolver's walk opens the final component with the caller's flags
+    let resolver = Resolver::new(PolicySet::new());
+    let res = resolver.safe_open("/flags/dir/made", libc::O_WRONLY | libc::O_CREAT);
+    assert_eq!(opened_path(res), PathBuf::from("/flags/dir/made"));
+    let path = CString::new("/flags/dir/made").unwrap();
+    assert_eq!(unsafe { mockfs::stat(path.as_ptr(), &mut st) }, 0);
+    assert_eq!(st.st_mode, libc::S_IFREG | 0o644);
+    let res = resolver.safe_open("/flags/link", libc::O_RDONLY | libc::O_CLOEXEC);
+    assert_eq!(opened_path(res), PathBuf::from("/flags/file"));
+}
+
+#[test]
+fn test_errno() {
+    let _fs = MockFs::new().install();
+    create("/errno/dir/file", FileType::Regular(String::new()));
+    create("/errno/loop", FileType::Symlink("loop".to_string()));
+    let open = |path: &str, flags: i32| {
+        let path = CString::new(path).unwrap();
+        assert_eq!(unsafe { mockfs::open(path.as_ptr(), flags) }, -1);
+        mockfs::errno()
+    };
+    assert_eq!(open("/errno/missing", libc::O_RDONLY), libc::ENOENT);
+    assert_eq!(open("/errno/missing/file", libc::O_RDONLY), libc::ENOENT);
+    assert_eq!(open("/errno/dir/file/x", libc::O_RDONLY), libc::ENOTDIR);
+    assert_eq!(open("/errno/loop", libc::O_RDONLY), libc::ELOOP);
+    assert_eq!(open("/errno/loop/x", libc::O_RDONLY), libc::ELOOP);
+    let excl = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+    assert_eq!(open("/errno/dir/file", excl), libc::EEXIST);
+
+    let path = CString::new("/errno/dir").unwrap();
+    assert_eq!(unsafe { mockfs::remove(path.as_ptr()) }, -1);
+    assert_eq!(mockfs::errno(), libc::ENOTEMPTY);
+    assert_eq!(unsafe { mockfs::close(-1) }, -1);
+    assert_eq!(mockfs::errno(), libc::EBADF);
+
+    // a successful call leaves errno as it was
+    let fd = open_dir("/errno/dir");
+    assert_eq!(mockfs::errno(), libc::EBADF);
+    unsafe { mockfs::close(fd) };
+
+    // errno is per thread
+    std::thread::spawn(|| assert_eq!(mockfs::errno(), 0))
+        .join()
+        .unwrap();
+
+    let err = mockfs::read_link("/errno/dir/file").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    let err = mockfs::read_link("/errno/missing").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+    let resolver = Resolver::new(PolicySet::new());
+    let res = resolver.safe_open("/errno/dir/file/x", libc::O_RDONLY);
+    assert!(
+        matches!(res, Err(OpenError::NotADirectory { .. })),
+        "{:?}",
+        res
+    );
+    let res = resolver.safe_open("/errno/missing", libc::O_RDONLY);
+    assert!(matches!(res, Err(OpenError::NotFound { .. })), "{:?}", res);
+}
+
+#[test]
+fn test_hard_links() {
+    let _fs = MockFs::new().install();
+    create("/links/file", FileType::Regular("shared".to_string()));
+    create("/links/dir/keep", FileType::Regular(String::new()));
+    let c = |path: &str| CString::new(path).unwrap();
+    let mut buf = [0u8; 16];
+
+    unsafe {
+        assert_eq!(
+            mockfs::link(c("/links/file").as_ptr(), c("/links/alias").as_ptr()),
+            0
+        );
+        // a write through one name shows through the other
+        let fd = mockfs::open(c("/links/alias").as_ptr(), libc::O_WRONLY | libc::O_APPEND);
+        assert_eq!(mockfs::write(fd, b"!".as_ptr().cast(), 1), 1);
+        mockfs::close(fd);
+        let fd = mockfs::open(c("/links/file").as_ptr(), libc::O_RDONLY);
+        assert_eq!(mockfs::read(fd, buf.as_mut_ptr().cast(), 16), 7);
+        assert_eq!(&buf[..7], b"shared!");
+
+        // the file outlives its names while it is open
+        assert_eq!(mockfs::unlink(c("/links/file").as_ptr()), 0);
+        assert_eq!(mockfs::unlink(c("/links/alias").as_ptr()), 0);
+        let missing = mockfs::open(c("/links/alias").as_ptr(), libc::O_RDONLY);
+        assert_eq!((missing, mockfs::errno()), (-1, libc::ENOENT));
+        assert_eq!(mockfs::pread(fd, buf.as_mut_ptr().cast(), 16, 0), 7);
+        mockfs::close(fd);
+
+        let fail = |res: i32| (res, mockfs::errno());
+        let (dir, keep) = (c("/links/dir"), c("/links/dir/keep"));
+        assert_eq!(
+            fail(mockfs::link(dir.as_ptr(), c("/links/d").as_ptr())),
+            (-1, libc::EPERM)
+        );
+        assert_eq!(
+            fail(mockfs::link(keep.as_ptr(), dir.as_ptr())),
+            (-1, libc::EEXIST)
+        );
+        let nowhere = c("/links/nowhere/keep");
+        assert_eq!(
+            fail(mockfs::link(keep.as_ptr(), nowhere.as_ptr())),
+            (-1, libc::ENOENT)
+        );
+        assert_eq!(fail(mockfs::unlink(dir.as_ptr())), (-1, libc::EISDIR));
+        assert_eq!(
+            fail(mockfs::unlink(c("/links/file").as_ptr())),
+            (-1, libc::ENOENT)
+        );
+    }
+
+    // a deny-list sees only the name, not that it is the same file
+    unsafe { mockfs::link(c("/links/dir/keep").as_ptr(), c("/links/other").as_ptr()) };
+    let resolver = Resolver::new(DenyList::new(["/links/dir/keep"]));
+    assert!(resolver
+        .safe_open("/links/dir/keep", libc::O_RDONLY)
+        .is_err());
+    assert_eq!(
+        opened_path(resolver.safe_open("/links/other", libc::O_RDONLY)),
+        PathBuf::from("/links/other")
+    );
+
+    let resolver = Resolver::new(ProtectedFiles::new(["/links/dir/keep"]));
+    match resolver.safe_open("/links/other", libc::O_RDONLY) {
+        Err(OpenError::AccessDenied { path, rule }) => {
+            assert_eq!(path, "/links/other");
+            assert_eq!(rule.as_deref(), Some("same file as /links/dir/keep"));
+        }
+        res => panic!("unexpected {:?}", res),
+    }
+}
+
+#[test]
+fn test_rename() {
+    let _fs = MockFs::new().install();
+    create("/moves/a", FileType::Regular("a".to_string()));
+    create("/moves/b", FileType::Regular("b".to_string()));
+    create("/moves/dir/sub/file", FileType::Regular(String::new()));
+    create("/moves/full/file", FileType::Regular(String::new()));
+    create("/moves/empty", FileType::Directory(Default::default()));
+    let c = |path: &str| CString::new(path).unwrap();
+    let read = |path: &str| {
+        let mut buf = [0u8; 16];
+        let fd = unsafe { mockfs::open(c(path).as_ptr(), libc::O_RDONLY) };
+        assert_ne!(fd, -1, "{}", path);
+        let n = unsafe { mockfs::read(fd, buf.as_mut_ptr().cast(), 16) };
+        unsafe { mockfs::close(fd) };
+        String::from_utf8(buf[..n as usize].to_vec()).unwrap()
+    };
+    let fail = |res: i32| (res, mockfs::errno());
+
+    unsafe {
+        // the target is replaced, in one step
+        assert_eq!(
+            mockfs::rename(c("/moves/a").as_ptr(), c("/moves/b").as_ptr()),
+            0
+        );
+        assert_eq!(read("/moves/b"), "a");
+        assert_eq!(
+            fail(mockfs::open(c("/moves/a").as_ptr(), libc::O_RDONLY)),
+            (-1, libc::ENOENT)
+        );
+
+        let (a, b) = (c("/moves/a"), c("/moves/b"));
+        let renameat2 = |old: &CString, new: &CString, flags| {
+            mockfs::renameat2(
+                libc::AT_FDCWD,
+                old.as_ptr(),
+                libc::AT_FDCWD,
+                new.as_ptr(),
+                flags,
+            )
+        };
+        create("/moves/a", FileType::Regular("new a".to_string()));
+        assert_eq!(
+            fail(renameat2(&a, &b, libc::RENAME_NOREPLACE)),
+            (-1, libc::EEXIST)
+        );
+        assert_eq!(renameat2(&a, &b, libc::RENAME_EXCHANGE), 0);
+        assert_eq!(
+            (read("/moves/a"), read("/moves/b")),
+            ("a".into(), "new a".into())
+        );
+        let nowhere = c("/moves/nowhere");
+        assert_eq!(
+            fail(renameat2(&a, &nowhere, libc::RENAME_EXCHANGE)),
+            (-1, libc::ENOENT)
+        );
+        assert_eq!(
+            fail(renameat2(
+                &a,
+                &b,
+                libc::RENAME_EXCHANGE | libc::RENAME_NOREPLACE
+            )),
+            (-1, libc::EINVAL)
+        );
+
+        let (dir, sub, full, empty) = (
+            c("/moves/dir"),
+            c("/moves/dir/sub/inside"),
+            c("/moves/full"),
+            c("/moves/empty"),
+        );
+        assert_eq!(fail(renameat2(&dir, &sub, 0)), (-1, libc::EINVAL));
+        assert_eq!(fail(renameat2(&dir, &a, 0)), (-1, libc::ENOTDIR));
+        assert_eq!(fail(renameat2(&a, &dir, 0)), (-1, libc::EISDIR));
+        assert_eq!(fail(renameat2(&dir, &full, 0)), (-1, libc::ENOTEMPTY));
+        assert_eq!(fail(renameat2(&nowhere, &a, 0)), (-1, libc::ENOENT));
+
+        // an open directory follows its entry, and so do lookups through it
+        let fd = open_dir("/moves/dir/sub");
+        assert_eq!(renameat2(&dir, &empty, 0), 0);
+        assert_eq!(
+            mockfs::read_link(format!("/proc/self/fd/{}", fd)).unwrap(),
+            PathBuf::from("/moves/empty/sub")
+        );
+        let file = mockfs::openat(fd, c("file").as_ptr(), libc::O_RDONLY, 0);
+        assert_eq!(
+            mockfs::read_link(format!("/proc/self/fd/{}", file)).unwrap(),
+            PathBuf::from("/moves/empty/sub/file")
+        );
+        assert_eq!(
+            mockfs::renameat(fd, c("file").as_ptr(), fd, c("moved").as_ptr()),
+            0
+        );
+        assert_eq!(read("/moves/empty/sub/moved"), "");
+        mockfs::close(file);
+        mockfs::close(fd);
+    }
+}
+
+#[test]
+fn test_directories_and_symlinks() {
+    let _fs = MockFs::new().install();
+    let c = |path: &str| CString::new(path).unwrap();
+    let fail = |res: i32| (res, mockfs::errno());
+
+    unsafe {
+        assert_eq!(mockfs::mkdir(c("/made").as_ptr(), 0o755), 0);
+        let dir = open_dir("/made");
+        assert_eq!(mockfs::mkdirat(dir, c("sub").as_ptr(), 0o755), 0);
+        assert_eq!(
+            fail(mockfs::mkdir(c("/made/sub").as_ptr(), 0o755)),
+            (-1, libc::EEXIST)
+        );
+        assert_eq!(
+            fail(mockfs::mkdir(c("/made/missing/sub").as_ptr(), 0o755)),
+            (-1, libc::ENOENT)
+        );
+
+        // the target is stored as is, whether or not it exists
+        assert_eq!(
+            mockfs::symlinkat(c("sub").as_ptr(), dir, c("link").as_ptr()),
+            0
+        );
+        assert_eq!(
+            mockfs::symlink(c("nowhere").as_ptr(), c("/made/dangling").as_ptr()),
+            0
+        );
+        assert_eq!(
+            mockfs::read_link("/made/dangling").unwrap(),
+            PathBuf::from("nowhere")
+        );
+        let through_link = open_dir("/made/link/");
+        assert_eq!(
+            mockfs::read_link(format!("/proc/self/fd/{}", through_link)).unwrap(),
+            PathBuf::from("/made/sub")
+        );
+        mockfs::close(through_link);
+        assert_eq!(
+            fail(mockfs::symlink(c("sub").as_ptr(), c("/made/link").as_ptr())),
+            (-1, libc::EEXIST)
+        );
+        assert_eq!(
+            fail(mockfs::symlink(c("").as_ptr(), c("/made/empty").as_ptr())),
+            (-1, libc::ENOENT)
+        );
+
+        create("/made/sub/file", FileType::Regular(String::new()));
+        assert_eq!(
+            fail(mockfs::rmdir(c("/made/sub").as_ptr())),
+            (-1, libc::ENOTEMPTY)
+        );
+        assert_eq!(
+            fail(mockfs::unlinkat(
+                dir,
+                c("sub/file").as_ptr(),
+                libc::AT_REMOVEDIR
+            )),
+            (-1, libc::ENOTDIR)
+        );
+        assert_eq!(
+            fail(mockfs::unlinkat(dir, c("sub").as_ptr(), 0)),
+            (-1, libc::EISDIR)
+        );
+        assert_eq!(
+            fail(mockfs::unlinkat(
+                dir,
+                c("sub").as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW
+            )),
+            (-1, libc::EINVAL)
+        );
+        assert_eq!(
+            fail(mockfs::rmdir(c("/made/.").as_ptr())),
+            (-1, libc::EINVAL)
+        );
+        // a symlink to a directory is removed like a file
+        assert_eq!(
+            fail(mockfs::rmdir(c("/made/link").as_ptr())),
+            (-1, libc::ENOTDIR)
+        );
+        assert_eq!(mockfs::unlinkat(dir, c("link").as_ptr(), 0), 0);
+        assert_eq!(mockfs::unlinkat(dir, c("sub/file").as_ptr(), 0), 0);
+        assert_eq!(
+            mockfs::unlinkat(dir, c("sub").as_ptr(), libc::AT_REMOVEDIR),
+            0
+        );
+        assert_eq!(
+            fail(mockfs::open(c("/made/sub").as_ptr(), libc::O_RDONLY)),
+            (-1, libc::ENOENT)
+        );
+
+        // a removed directory has no path to look anything up from
+        let sub = c("/made/gone");
+        assert_eq!(mockfs::mkdir(sub.as_ptr(), 0o755), 0);
+        let gone = open_dir("/made/gone");
+        assert_eq!(mockfs::rmdir(sub.as_ptr()), 0);
+        assert_eq!(
+            fail(mockfs::mkdirat(gone, c("sub").as_ptr(), 0o755)),
+            (-1, libc::ENOENT)
+        );
+        mockfs::close(gone);
+        mockfs::close(dir);
+    }
+}
+
+#[test]
+fn test_stat() {
+    let _fs = MockFs::new().install();
+    create("/meta/file", FileType::Regular("12345".to_string()));
+    create("/meta/link", FileType::Symlink("file".to_string()));
+    create("/meta/dir_link", FileType::Symlink("dir".to_string()));
+    create("/meta/dir/inner", FileType::Regular(String::new()));
+    let c = |path: &str| CString::new(path).unwrap();
+    let stat_with = |f: unsafe fn(*const libc::c_char, *mut libc::stat) -> i32, path: &str| {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { f(c(path).as_ptr(), &mut st) }, 0, "{}", path);
+        st
+    };
+
+    let file = stat_with(mockfs::stat, "/meta/file");
+    assert_eq!(file.st_mode, libc::S_IFREG | 0o644);
+    assert_eq!((file.st_size, file.st_nlink), (5, 1));
+    assert_eq!((file.st_uid, file.st_gid), (0, 0));
+    assert_eq!((file.st_blksize, file.st_blocks), (4096, 1));
+    // stat follows the link, lstat does not
+    let through_link = stat_with(mockfs::stat, "/meta/link");
+    assert_eq!(
+        (through_link.st_dev, through_link.st_ino),
+        (file.st_dev, file.st_ino)
+    );
+    let link = stat_with(mockfs::lstat, "/meta/link");
+    assert_eq!(link.st_mode, libc::S_IFLNK | 0o777);
+    assert_eq!(link.st_size, "file".len() as libc::off_t);
+    assert_ne!(link.st_ino, file.st_ino);
+    // unless a trailing slash asks for the directory
+    let dir = stat_with(mockfs::stat, "/meta/dir");
+    assert_eq!(dir.st_mode, libc::S_IFDIR | 0o755);
+    assert_eq!(
+        stat_with(mockfs::lstat, "/meta/dir_link/").st_ino,
+        dir.st_ino
+    );
+
+    unsafe {
+        let fd = open_dir("/meta/dir");
+        let mut st: libc::stat = std::mem::zeroed();
+        assert_eq!(mockfs::fstatat(fd, c("inner").as_ptr(), &mut st, 0), 0);
+        let inner = st;
+        assert_eq!(
+            mockfs::fstatat(fd, c("").as_ptr(), &mut st, libc::AT_EMPTY_PATH),
+            0
+        );
+        assert_eq!(st.st_ino, dir.st_ino);
+        let fail = |res: i32| (res, mockfs::errno());
+        assert_eq!(
+            fail(mockfs::fstatat(fd, c("").as_ptr(), &mut st, 0)),
+            (-1, libc::ENOENT)
+        );
+        assert_eq!(
+            fail(mockfs::fstatat(
+                fd,
+                c("inner").as_ptr(),
+                &mut st,
+                libc::O_RDWR
+            )),
+            (-1, libc::EINVAL)
+        );
+        assert_eq!(
+            fail(mockfs::lstat(c("/meta/missing").as_ptr(), &mut st)),
+            (-1, libc::ENOENT)
+        );
+
+        // a write changes the file, a new entry its directory
+        let out = mockfs::open(c("/meta/dir/inner").as_ptr(), libc::O_WRONLY);
+        assert_eq!(mockfs::write(out, b"x".as_ptr().cast(), 1), 1);
+        assert_eq!(mockfs::fstat(out, &mut st), 0);
+        assert!(st.st_mtime > inner.st_mtime && st.st_ctime == st.st_mtime);
+        assert_eq!(st.st_atime, inner.st_atime);
+        mockfs::close(out);
+        assert_eq!(mockfs::mkdirat(fd, c("sub").as_ptr(), 0o755), 0);
+        assert_eq!(mockfs::fstat(fd, &mut st), 0);
+        assert!(st.st_mtime > dir.st_mtime);
+        // a new link changes only the metadata
+        let before = stat_with(mockfs::stat, "/meta/file");
+        assert_eq!(
+            mockfs::link(c("/meta/file").as_ptr(), c("/meta/alias").as_ptr()),
+            0
+        );
+        let after = stat_with(mockfs::stat, "/meta/alias");
+        assert_eq!((after.st_nlink, after.st_mtime), (2, before.st_mtime));
+        assert!(after.st_ctime > before.st_ctime);
+        mockfs::close(fd);
+    }
+}
+
+#[test]
+fn test_permissions() {
+    let _fs = MockFs::new().install();
+    let c = |path: &str| CString::new(path).unwrap();
+    let fail = |res: i32| (res, mockfs::errno());
+    create(
+        "/perms/home/alice/notes",
+        FileType::Regular("mine".to_string()),
+    );
+    create(
+        "/perms/secret/file",
+        FileType::Regular("hidden".to_string()),
+    );
+    create("/perms/shadow", FileType::Regular("hashes".to_string()));
+    create("/perms/group", FileType::Regular("shared".to_string()));
+    create("/perms/tmp/bobs", FileType::Regular(String::new()));
+    unsafe {
+        assert_eq!(
+            mockfs::chown(c("/perms/home/alice").as_ptr(), 1000, 1000),
+            0
+        );
+        assert_eq!(
+            mockfs::chown(c("/perms/home/alice/notes").as_ptr(), 1000, 1000),
+            0
+        );
+        assert_eq!(
+            mockfs::chmod(c("/perms/home/alice/notes").as_ptr(), 0o600),
+            0
+        );
+        assert_eq!(mockfs::chmod(c("/perms/secret").as_ptr(), 0o700), 0);
+        assert_eq!(mockfs::chmod(c("/perms/shadow").as_ptr(), 0o640), 0);
+        assert_eq!(mockfs::chown(c("/perms/group").as_ptr(), 0, 50), 0);
+        assert_eq!(mockfs::chmod(c("/perms/group").as_ptr(), 0o660), 0);
+        assert_eq!(mockfs::chmod(c("/perms/tmp").as_ptr(), 0o1777), 0);
+        assert_eq!(mockfs::chown(c("/perms/tmp/bobs").as_ptr(), 1001, 1001), 0);
+    }
+
+    // root may do anything
+    let open = |path: &str, flags| unsafe { mockfs::open(c(path).as_ptr(), flags) };
+    let fd = open("/perms/home/alice/notes", libc::O_RDWR);
+    assert_ne!(fd, -1);
+    unsafe { mockfs::close(fd) };
+
+    let mut alice = mockfs::Credentials::user(1000, 1000);
+    alice.groups.push(50);
+    mockfs::set_credentials(alice);
+    let fd = open("/perms/home/alice/notes", libc::O_RDWR);
+    assert_ne!(fd, -1);
+    unsafe { mockfs::close(fd) };
+    let fd = open("/perms/group", libc::O_RDWR);
+    assert_ne!(fd, -1);
+    unsafe { mockfs::close(fd) };
+    assert_eq!(
+        fail(open("/perms/shadow", libc::O_RDONLY)),
+        (-1, libc::EACCES)
+    );
+    assert_eq!(
+        fail(open("/perms/secret/file", libc::O_RDONLY)),
+        (-1, libc::EACCES)
+    );
+    // nor may the file be found at all, not even to stat it
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    assert_eq!(
+        fail(unsafe { mockfs::stat(c("/perms/secret/file").as_ptr(), &mut st) }),
+        (-1, libc::EACCES)
+    );
+    // O_PATH needs no access to the file itself
+    let fd = open("/perms/shadow", libc::O_PATH);
+    assert_ne!(fd, -1);
+    unsafe { mockfs::close(fd) };
+
+    unsafe {
+        // creating needs write access to the directory
+        assert_eq!(
+            fail(mockfs::open(
+                c("/perms/new").as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT
+            )),
+            (-1, libc::EACCES)
+        );
+        assert_eq!(
+            fail(mockfs::mkdir(c("/perms/dir").as_ptr(), 0o777)),
+            (-1, libc::EACCES)
+        );
+        assert_eq!(mockfs::mkdir(c("/perms/home/alice/dir").as_ptr(), 0o777), 0);
+        assert_eq!(
+            mockfs::stat(c("/perms/home/alice/dir").as_ptr(), &mut st),
+            0
+        );
+        assert_eq!(
+            (st.st_mode & 0o7777, st.st_uid, st.st_gid),
+            (0o755, 1000, 1000)
+        );
+
+        // only the owner changes the mode, only root the owner
+        assert_eq!(
+            fail(mockfs::chmod(c("/perms/shadow").as_ptr(), 0o666)),
+            (-1, libc::EPERM)
+        );
+        let notes = c("/perms/home/alice/notes");
+        assert_eq!(
+            fail(mockfs::chown(notes.as_ptr(), 0, libc::gid_t::MAX)),
+            (-1, libc::EPERM)
+        );
+        assert_eq!(mockfs::chown(notes.as_ptr(), libc::uid_t::MAX, 50), 0);
+        assert_eq!(
+            fail(mockfs::chown(notes.as_ptr(), libc::uid_t::MAX, 0)),
+            (-1, libc::EPERM)
+        );
+
+        // in a sticky directory the files of others stay put
+        let (bobs, mine) = (c("/perms/tmp/bobs"), c("/perms/tmp/mine"));
+        assert_eq!(fail(mockfs::unlink(bobs.as_ptr())), (-1, libc::EPERM));
+        assert_eq!(
+            fail(mockfs::rename(bobs.as_ptr(), mine.as_ptr())),
+            (-1, libc::EPERM)
+        );
+        assert_eq!(mockfs::symlink(c("bobs").as_ptr(), mine.as_ptr()), 0);
+        assert_eq!(mockfs::unlink(mine.as_ptr()), 0);
+        // and their files cannot be linked to, unless readable and writable
+        let link = c("/perms/home/alice/shadow");
+        assert_eq!(
+            fail(mockfs::link(c("/perms/shadow").as_ptr(), link.as_ptr())),
+            (-1, libc::EPERM)
+        );
+        assert_eq!(mockfs::link(c("/perms/group").as_ptr(), link.as_ptr()), 0);
+    }
+
+    // the resolver reports what the mock refuses
+    let resolver = Resolver::new(PolicySet::new());
+    match resolver.safe_open("/perms/secret/file", libc::O_RDONLY) {
+        Err(OpenError::Os { errno, .. }) => assert_eq!(errno, libc::EACCES),
+        res => panic!("unexpected {:?}", res),
+    }
+    assert!(resolver.safe_open("/perms/group", libc::O_RDONLY).is_ok());
+}
+
+/// A directory that may be searched but not read is walked through, as
+/// the kernel's own lookup does, with `openat2` and without.
+#[test]
+fn test_search_only_directory() {
+    let _fs = MockFs::new().install();
+    mockfs::set_openat2_supported(true);
+    create("/search/dir/file", FileType::Regular("found".to_string()));
+    let dir = CString::new("/search/dir").unwrap();
+    assert_eq!(unsafe { mockfs::chmod(dir.as_ptr(), 0o711) }, 0);
+    mockfs::set_credentials(mockfs::Credentials::user(1000, 1000));
+    assert_eq!(unsafe { mockfs::open(dir.as_ptr(), libc::O_RDONLY) }, -1);
+    assert_eq!(mockfs::errno(), libc::EACCES);
+
+    for fast in [true, false] {
+        let resolver = Resolver::new(PolicySet::new()).use_openat2(fast);
+        let calls = mockfs::openat2_calls();
+        assert_eq!(
+            opened_path(resolver.safe_open("/search/dir/file", libc::O_RDONLY)),
+            PathBuf::from("/search/dir/file"),
+            "{}",
+            fast
+        );
+        assert_eq!(mockfs::openat2_calls(), calls + fast as usize);
+    }
+}
+
+/// The names `readdir` yields for the stream, `.` and `..` left out, with
+/// their `d_type`.
+fn read_dir(dir: *mut mockfs::Dir) -> Vec<(String, u8)> {
+    let mut entries = Vec::new();
+    loop {
+        let entry = unsafe { mockfs::readdir(dir) };
+        if entry.is_null() {
+            return entries;
+        }
+        let entry = unsafe { &*entry };
+        let name = unsafe { std::ffi::CStr::from_ptr(entry.d_name.as_ptr()) };
+        let name = name.to_str().unwrap().to_string();
+        if name != "." && name != ".." {
+            entries.push((name, entry.d_type));
+        }
+    }
+}
+
+/// Every file beneath `root`, walked with the resolver so that the policy
+/// and the confinement to `root` apply to each step.
+fn walk(resolver: &Resolver, root: i32, path: &str, files: &mut Vec<String>) {
+    let Ok(fd) = resolver.safe_open_beneath(root, path, libc::O_RDONLY | libc::O_DIRECTORY) else {
+        return;
+    };
+    let dir = unsafe { mockfs::fdopendir(fd.into_raw()) };
+    assert!(!dir.is_null());
+    for (name, d_type) in read_dir(dir) {
+        let path = format!("{}/{}", path, name);
+        match d_type {
+            libc::DT_DIR => walk(resolver, root, &path, files),
+            // symlinks are followed, by the resolver, only to files
+            _ if resolver
+                .safe_open_beneath(root, &path, libc::O_RDONLY)
+                .is_ok() =>
+            {
+                files.push(path)
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(unsafe { mockfs::closedir(dir) }, 0);
+}
+
+#[test]
+fn test_readdir() {
+    let _fs = MockFs::new().install();
+    create("/listing/b", FileType::Regular(String::new()));
+    create("/listing/a/inner", FileType::Regular(String::new()));
+    create("/listing/a/deeper/file", FileType::Regular(String::new()));
+    create("/listing/c", FileType::Symlink("a/inner".to_string()));
+    create("/listing/escape", FileType::Symlink("/etc".to_string()));
+    create("/listing/a/secret", FileType::Regular(String::new()));
+    let c = |path: &str| CString::new(path).unwrap();
+
+    unsafe {
+        let dir = mockfs::opendir(c("/listing").as_ptr());
+        assert!(!dir.is_null());
+        let expected = [
+            ("a", libc::DT_DIR),
+            ("b", libc::DT_REG),
+            ("c", libc::DT_LNK),
+            ("escape", libc::DT_LNK),
+        ];
+        let expected: Vec<_> = expected.iter().map(|&(n, t)| (n.to_string(), t)).collect();
+        assert_eq!(read_dir(dir), expected);
+        // the same again after a rewind
+        let fd = mockfs::dirfd(dir);
+        assert_eq!(mockfs::lseek(fd, 0, libc::SEEK_SET), 0);
+        assert_eq!(read_dir(dir), expected);
+        assert_eq!(mockfs::closedir(dir), 0);
+
+        // records are read a buffer at a time, never split
+        let fd = open_dir("/listing");
+        let mut buf = [0u8; 64];
+        let mut names = Vec::new();
+        loop {
+            let read = mockfs::getdents64(fd, buf.as_mut_ptr().cast(), buf.len());
+            assert!(read >= 0);
+            if read == 0 {
+                break;
+            }
+            let mut pos = 0;
+            while pos < read as usize {
+                let reclen = u16::from_ne_bytes([buf[pos + 16], buf[pos + 17]]) as usize;
+                let name = std::ffi::CStr::from_bytes_until_nul(&buf[pos + 19..pos + reclen]);
+                names.push(name.unwrap().to_str().unwrap().to_string());
+                pos += reclen;
+            }
+        }
+        assert_eq!(names, [".", "..", "a", "b", "c", "escape"]);
+        let fail = |res: isize| (res, mockfs::errno());
+        assert_eq!(mockfs::lseek(fd, 0, libc::SEEK_SET), 0);
+        assert_eq!(
+            fail(mockfs::getdents64(fd, buf.as_mut_ptr().cast(), 8)),
+            (-1, libc::EINVAL)
+        );
+        mockfs::close(fd);
+        let file = mockfs::open(c("/listing/b").as_ptr(), libc::O_RDONLY);
+        assert_eq!(
+            fail(mockfs::getdents64(file, buf.as_mut_ptr().cast(), buf.len())),
+            (-1, libc::ENOTDIR)
+        );
+        assert!(mockfs::fdopendir(file).is_null());
+        assert_eq!(mockfs::errno(), libc::ENOTDIR);
+        mockfs::close(file);
+        let path_only = mockfs::open(c("/listing").as_ptr(), libc::O_PATH);
+        assert_eq!(
+            fail(mockfs::getdents64(
+                path_only,
+                buf.as_mut_ptr().cast(),
+                buf.len()
+            )),
+            (-1, libc::EBADF)
+        );
+        mockfs::close(path_only);
+    }
+
+    let root = open_dir("/listing");
+    let resolver = Resolver::new(DenyList::new(["/listing/a/secret"]));
+    let mut files = Vec::new();
+    walk(&resolver, root, ".", &mut files);
+    assert_eq!(files, ["./a/deeper/file", "./a/inner", "./b", "./c"]);
+    unsafe { mockfs::close(root) };
+}
+
+#[test]
+fn test_isolated_instances() {
+    let c = |path: &str| CString::new(path).unwrap();
+    let read = move |path: &str| {
+        let fd = unsafe { mockfs::open(c(path).as_ptr(), libc::O_RDONLY) };
+        let mut buf = [0u8; 32];
+        let n = unsafe { mockfs::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        unsafe { mockfs::close(fd) };
+        String::from_utf8(buf[..n.max(0) as usize].to_vec()).unwrap()
+    };
+
+    // each has its own tree, descriptors and working directory
+    let (first, second) = (MockFs::new(), MockFs::new());
+    let threads = [(first.clone(), "first"), (second.clone(), "second")].map(|(fs, name)| {
+        std::thread::spawn(move || {
+            let _fs = fs.install();
+            mockfs::initialize_mockfs();
+            // a second time leaves the files alone
+            mockfs::initialize_mockfs();
+            create("/isolated/file", FileType::Regular(name.to_string()));
+            assert_eq!(open_dir("/isolated"), 3);
+            assert_eq!(unsafe { mockfs::chdir(c("/isolated").as_ptr()) }, 0);
+            assert_eq!(read("file"), name);
+        })
+    });
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    first.enter(|| {
+        assert_eq!(read("/isolated/file"), "first");
+        assert_eq!(open_fds(), [0, 1, 2, 3]);
+    });
+    second.enter(|| assert_eq!(read("file"), "second"));
+
+    // installs nest, and a thread that installs nothing has the default
+    let _first = first.install();
+    second.enter(|| assert_eq!(read("/isolated/file"), "second"));
+    assert_eq!(read("/isolated/file"), "first");
+    std::thread::spawn(move || assert_eq!(read("/isolated/file"), ""))
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_fixture() {
+    let c = |path: &str| CString::new(path).unwrap();
+    let fixture = Fixture::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/shared_host.fixture"
+    ))
+    .unwrap();
+    let _fs = fixture.build().unwrap().install();
+    let stat = |path: &str| {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(
+            unsafe { mockfs::lstat(c(path).as_ptr(), &mut st) },
+            0,
+            "{}",
+            path
+        );
+        st
+    };
+    for (path, mode, uid, gid) in [
+        ("/etc", libc::S_IFDIR | 0o755, 0, 0),
+        ("/etc/shadow", libc::S_IFREG | 0o600, 0, 0),
+        ("/home/alice", libc::S_IFDIR | 0o700, 1000, 1000),
+        ("/home/alice/notes", libc::S_IFREG | 0o600, 1000, 1000),
+        ("/home/alice/passwd", libc::S_IFLNK | 0o777, 0, 0),
+        ("/srv/shared/plan", libc::S_IFREG | 0o660, 1001, 50),
+        ("/tmp", libc::S_IFDIR | 0o1777, 0, 0),
+        ("/tmp/bobs", libc::S_IFREG | 0o644, 1001, 1001),
+    ] {
+        let st = stat(path);
+        assert_eq!(
+            (st.st_mode, st.st_uid, st.st_gid),
+            (mode, uid, gid),
+            "{}",
+            path
+        );
+    }
+    assert_eq!(stat("/home/bob/shadow").st_ino, stat("/etc/shadow").st_ino);
+    assert_eq!(stat("/etc/shadow").st_nlink, 2);
+
+    let resolver = Resolver::new(PolicySet::new());
+    let path = opened_path(resolver.safe_open("/home/alice/passwd", libc::O_RDONLY));
+    assert_eq!(path, PathBuf::from("/etc/passwd"));
+    let fd = resolver
+        .safe_open("/srv/shared/plan", libc::O_RDONLY)
+        .unwrap()
+        .into_raw();
+    let mut buf = [0u8; 64];
+    let n = unsafe { mockfs::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+    assert_eq!(&buf[..n as usize], b"step 1\tstep 2\n");
+
+    // everything is applied or nothing is
+    let err = Fixture::new()
+        .file("/etc/motd", "hello")
+        .hard_link("/etc/home", "/home")
+        .apply()
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        FixtureError::Apply { ref path, errno: libc::EPERM } if path == "/etc/home"
+    ));
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    assert_eq!(
+        unsafe { mockfs::lstat(c("/etc/motd").as_ptr(), &mut st) },
+        -1
+    );
+    assert_eq!(mockfs::errno(), libc::ENOENT);
+    assert!(matches!(
+        Fixture::new().dir("/etc/passwd").apply(),
+        Err(FixtureError::Apply {
+            errno: libc::EEXIST,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_snapshot() {
+    let c = |path: &str| CString::new(path).unwrap();
+    let fs = Fixture::new()
+        .file("/snap/file", "before")
+        .file("/snap/gone", "")
+        .symlink("/snap/link", "file")
+        .dir("/snap/a")
+        .dir("/snap/b")
+        .build()
+        .unwrap();
+    let _fs = fs.install();
+    let before = fs.snapshot();
+    let ino = |path: &str| {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { mockfs::lstat(c(path).as_ptr(), &mut st) }, 0);
+        st.st_ino
+    };
+    let (file, a, b) = (ino("/snap/file"), ino("/snap/a"), ino("/snap/b"));
+    let new = unsafe {
+        let fd = mockfs::open(c("/snap/file").as_ptr(), libc::O_WRONLY | libc::O_TRUNC);
+        mockfs::write(fd, b"after".as_ptr().cast(), 5);
+        mockfs::close(fd);
+        mockfs::unlink(c("/snap/gone").as_ptr());
+        mockfs::unlink(c("/snap/link").as_ptr());
+        mockfs::symlink(c("/etc/passwd").as_ptr(), c("/snap/link").as_ptr());
+        mockfs::renameat2(
+            libc::AT_FDCWD,
+            c("/snap/a").as_ptr(),
+            libc::AT_FDCWD,
+            c("/snap/b").as_ptr(),
+            libc::RENAME_EXCHANGE,
+        );
+        mockfs::open(
+            c("/snap/new").as_ptr(),
+            libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+        )
+    };
+    assert_ne!(new, -1);
+    let after = fs.snapshot();
+
+    let directory = |ino| Entry {
+        ino,
+        kind: EntryKind::Directory,
+    };
+    let changes = snapshot::diff(&before, &after);
+    let printed: Vec<String> = changes.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        changes,
+        [
+            Change::Retargeted {
+                path: "/snap/a".to_string(),
+                from: directory(a),
+                to: directory(b),
+            },
+            Change::Retargeted {
+                path: "/snap/b".to_string(),
+                from: directory(b),
+                to: directory(a),
+            },
+            Change::Modified {
+                path: "/snap/file".to_string(),
+                entry: Entry {
+                    ino: file,
+                    kind: EntryKind::Regular,
+                },
+            },
+            Change::Removed {
+                path: "/snap/gone".to_string(),
+                entry: Entry {
+                    ino: file + 1,
+                    kind: EntryKind::Regular,
+                },
+            },
+            Change::Retargeted {
+                path: "/snap/link".to_string(),
+                from: Entry {
+                    ino: file + 2,
+                    kind: EntryKind::Symlink("file".to_string()),
+                },
+                to: Entry {
+                    ino: ino("/snap/link"),
+                    kind: EntryKind::Symlink("/etc/passwd".to_string()),
+                },
+            },
+            Change::Created {
+                path: "/snap/new".to_string(),
+                entry: Entry {
+                    ino: ino("/snap/new"),
+                    kind: EntryKind::Regular,
+                },
+            },
+        ],
+        "{:#?}",
+        printed
+    );
+    assert_eq!(
+        printed[0],
+        format!(
+            "retargeted /snap/a: directory (inode {}) -> directory (inode {})",
+            a, b
+        )
+    );
+    assert!(snapshot::diff(&after, &after).is_empty());
+
+    // the file created since stays open, and its number is not handed out again
+    let new_ino = ino("/snap/new");
+    fs.restore(&before);
+    assert!(snapshot::diff(&before, &fs.snapshot()).is_empty());
+    let mut buf = [0u8; 8];
+    unsafe {
+        assert_eq!(mockfs::write(new, b"still".as_ptr().cast(), 5), 5);
+        assert_eq!(mockfs::pread(new, buf.as_mut_ptr().cast(), buf.len(), 0), 5);
+        assert_eq!(mockfs::close(new), 0);
+    }
+    assert_eq!(&buf[..5], b"still");
+    create("/snap/newer", FileType::Regular(String::new()));
+    assert!(ino("/snap/newer") > new_ino);
+}
+
+#[test]
+fn test_import() {
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let c = |path: &str| CString::new(path).unwrap();
+    let dir = scratch_dir("import");
+    fs::create_dir(dir.join("etc")).unwrap();
+    fs::write(dir.join("etc/passwd"), "root:x:0:0").unwrap();
+    fs::set_permissions(dir.join("etc/passwd"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::hard_link(dir.join("etc/passwd"), dir.join("alias")).unwrap();
+    symlink("/etc/passwd", dir.join("absolute")).unwrap();
+    fs::write(dir.join("large"), "0123456789").unwrap();
+    let fifo = c(dir.join("fifo").to_str().unwrap());
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+    let fixture = Import::new().max_file_size(4).read(&dir, "/host").unwrap();
+    let _fs = fixture.build().unwrap().install();
+    let stat = |path: &str| {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        let res = unsafe { mockfs::lstat(c(path).as_ptr(), &mut st) };
+        (res, st)
+    };
+    let read = |path: &str| {
+        let fd = unsafe { mockfs::open(c(path).as_ptr(), libc::O_RDONLY) };
+        let mut buf = [0u8; 64];
+        let n = unsafe { mockfs::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        unsafe { mockfs::close(fd) };
+        String::from_utf8_lossy(&buf[..n as usize]).into_owned()
+    };
+    let uid = unsafe { libc::getuid() };
+    let (_, passwd) = stat("/host/etc/passwd");
+    assert_eq!(passwd.st_mode, libc::S_IFREG | 0o600);
+    assert_eq!((passwd.st_uid, passwd.st_nlink), (uid, 2));
+    assert_eq!(stat("/host/alias").1.st_ino, passwd.st_ino);
+    assert_eq!(read("/host/alias"), "root");
+    assert_eq!(read("/host/large"), "0123");
+    assert_eq!(
+        stat("/host/absolute").1.st_mode & libc::S_IFMT,
+        libc::S_IFLNK
+    );
+    assert_eq!(
+        mockfs::read_link("/host/absolute").unwrap(),
+        PathBuf::from("/etc/passwd")
+    );
+    assert_eq!(stat("/host/fifo").0, -1);
+
+    let err = Import::new()
+        .max_file_size(4)
+        .max_total_size(6)
+        .read(&dir, "/host")
+        .unwrap_err();
+    assert!(
+        matches!(&err, FixtureError::TooLarge { path } if path.ends_with("large")),
+        "{}",
+        err
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
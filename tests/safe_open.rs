@@ -0,0 +1,531 @@
+#![cfg(not(feature = "mock"))]
+
+use rust_sandbox::fd::FileId;
+use rust_sandbox::mockfs::fixture::Fixture;
+use rust_sandbox::policy::file::FilePolicy;
+use rust_sandbox::policy::{DenyList, Glob, Policy, PolicySet, ProtectedFiles};
+use rust_sandbox::{DotDot, FileSystem, LibcFs, OpenError, Resolver};
+use std::fs;
+use std::io::Read;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs as unix_fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust_sandbox-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn resolver() -> Resolver {
+    Resolver::new(PolicySet::new())
+}
+
+fn read_all(file: &mut fs::File) -> String {
+    let mut content = String::new();
+    file.read_to_string(&mut content).unwrap();
+    content
+}
+
+#[test]
+fn test_open_regular_file() {
+    let dir = scratch_dir("regular");
+    fs::write(dir.join("file"), "content").unwrap();
+
+    let fd = resolver()
+        .safe_open(dir.join("file").to_str().unwrap(), libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(read_all(&mut fd.into()), "content");
+}
+
+#[test]
+fn test_free_functions() {
+    let dir = scratch_dir("free");
+    fs::write(dir.join("file"), "content").unwrap();
+
+    let fd = rust_sandbox::safe_open(dir.join("file").to_str().unwrap(), libc::O_RDONLY).unwrap();
+    assert_eq!(read_all(&mut fd.into()), "content");
+    let root = fs::File::open(&dir).unwrap();
+    let fd = rust_sandbox::safe_openat(root.as_raw_fd(), "file", libc::O_RDONLY).unwrap();
+    assert_eq!(read_all(&mut fd.into()), "content");
+}
+
+#[test]
+fn test_follow_symlinks() {
+    let dir = scratch_dir("symlinks");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/file"), "content").unwrap();
+    unix_fs::symlink("sub/file", dir.join("relative")).unwrap();
+    unix_fs::symlink(dir.join("sub/file"), dir.join("absolute")).unwrap();
+
+    for name in ["relative", "absolute"] {
+        let fd = resolver()
+            .safe_open(dir.join(name).to_str().unwrap(), libc::O_RDONLY)
+            .unwrap();
+        assert_eq!(read_all(&mut fd.into()), "content");
+    }
+}
+
+#[test]
+fn test_openat_relative_to_dirfd() {
+    let dir = scratch_dir("openat");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/file"), "content").unwrap();
+
+    let dirfd = fs::File::open(&dir).unwrap();
+    let fd = resolver()
+        .safe_openat(dirfd.as_raw_fd(), "sub/file", libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(read_all(&mut fd.into()), "content");
+}
+
+#[test]
+fn test_missing_file() {
+    let dir = scratch_dir("missing");
+    let missing = dir.join("missing");
+    match resolver().safe_open(missing.to_str().unwrap(), libc::O_RDONLY) {
+        Err(OpenError::NotFound { path }) => assert_eq!(path, missing.to_str().unwrap()),
+        res => panic!("unexpected {:?}", res),
+    }
+
+    fs::write(dir.join("file"), "").unwrap();
+    let res = resolver().safe_open(dir.join("file/child").to_str().unwrap(), libc::O_RDONLY);
+    assert!(matches!(res, Err(OpenError::NotADirectory { .. })));
+}
+
+#[test]
+fn test_deny_through_symlink() {
+    let dir = scratch_dir("deny");
+    fs::write(dir.join("secret"), "secret").unwrap();
+    unix_fs::symlink("secret", dir.join("innocent")).unwrap();
+    let secret = dir.join("secret").to_str().unwrap().to_string();
+
+    let resolver = Resolver::new(DenyList::new([secret.clone()]));
+    for name in ["secret", "innocent"] {
+        let res = resolver.safe_open(dir.join(name).to_str().unwrap(), libc::O_RDONLY);
+        assert!(matches!(res, Err(OpenError::AccessDenied { .. })));
+    }
+
+    let resolver =
+        Resolver::new(PolicySet::new().with(Glob::deny(&format!("{}/sec*", dir.display()))));
+    let res = resolver.safe_open(dir.join("innocent").to_str().unwrap(), libc::O_RDONLY);
+    match res {
+        Err(OpenError::AccessDenied { path, rule }) => {
+            assert_eq!(path, secret);
+            assert_eq!(rule, Some(format!("pattern {}/sec*", dir.display())));
+        }
+        res => panic!("unexpected {:?}", res),
+    }
+}
+
+#[test]
+fn test_symlink_hops() {
+    let dir = scratch_dir("hops");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/file"), "content").unwrap();
+    unix_fs::symlink("sub", dir.join("dir_link")).unwrap();
+    unix_fs::symlink("dir_link/file", dir.join("nested")).unwrap();
+    unix_fs::symlink("nested", dir.join("chain")).unwrap();
+    unix_fs::symlink("loop_b", dir.join("loop_a")).unwrap();
+    unix_fs::symlink("loop_a", dir.join("loop_b")).unwrap();
+
+    let chain = dir.join("chain");
+    let fd = resolver()
+        .safe_open(chain.to_str().unwrap(), libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(read_all(&mut fd.into()), "content");
+
+    let res = resolver()
+        .max_symlink_hops(2)
+        .safe_open(chain.to_str().unwrap(), libc::O_RDONLY);
+    assert!(matches!(res, Err(OpenError::SymlinkLoop { limit: 2, .. })));
+
+    let res = resolver().safe_open(dir.join("loop_a").to_str().unwrap(), libc::O_RDONLY);
+    assert!(matches!(res, Err(OpenError::SymlinkLoop { limit: 40, .. })));
+}
+
+#[test]
+fn test_dot_and_empty_components() {
+    let dir = scratch_dir("dots");
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    fs::write(dir.join("a/b/c"), "content").unwrap();
+    let dir = dir.to_str().unwrap();
+
+    for path in ["a/./b//c", "a/b/../b/c", "a/../a/b/./c"] {
+        let fd = resolver()
+            .safe_open(&format!("{}/{}", dir, path), libc::O_RDONLY)
+            .unwrap();
+        assert_eq!(read_all(&mut fd.into()), "content", "{}", path);
+    }
+
+    for path in ["a/b/", "a/b/.", "a/b/.."] {
+        assert!(resolver()
+            .safe_open(&format!("{}/{}", dir, path), libc::O_RDONLY)
+            .is_ok());
+    }
+    assert!(resolver().safe_open("/", libc::O_RDONLY).is_ok());
+    assert!(resolver().safe_open(".", libc::O_RDONLY).is_ok());
+
+    for path in ["a/b/c/", "a/b/c/."] {
+        let res = resolver().safe_open(&format!("{}/{}", dir, path), libc::O_RDONLY);
+        assert!(
+            matches!(res, Err(OpenError::NotADirectory { .. })),
+            "{}",
+            path
+        );
+    }
+    let res = resolver().safe_open("", libc::O_RDONLY);
+    assert!(matches!(res, Err(OpenError::NotFound { .. })));
+}
+
+#[test]
+fn test_dot_dot_modes() {
+    let dir = scratch_dir("dotdot");
+    fs::create_dir_all(dir.join("real/sub")).unwrap();
+    fs::write(dir.join("real/file"), "physical").unwrap();
+    fs::write(dir.join("file"), "lexical").unwrap();
+    unix_fs::symlink("real/sub", dir.join("link")).unwrap();
+    let path = dir.join("link/../file");
+
+    let fd = resolver()
+        .safe_open(path.to_str().unwrap(), libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(read_all(&mut fd.into()), "physical");
+
+    let fd = resolver()
+        .dot_dot(DotDot::Lexical)
+        .safe_open(path.to_str().unwrap(), libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(read_all(&mut fd.into()), "lexical");
+}
+
+#[test]
+fn test_forbid_escape() {
+    let dir = scratch_dir("escape");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("file"), "content").unwrap();
+    unix_fs::symlink("../file", dir.join("sub/up")).unwrap();
+
+    let dirfd = fs::File::open(dir.join("sub")).unwrap();
+    let resolver = resolver().forbid_escape(true);
+    for path in ["../file", "up", "./../sub/file"] {
+        let res = resolver.safe_openat(dirfd.as_raw_fd(), path, libc::O_RDONLY);
+        assert!(
+            matches!(res, Err(OpenError::EscapesRoot { .. })),
+            "{}: {:?}",
+            path,
+            res
+        );
+    }
+
+    let dirfd = fs::File::open(&dir).unwrap();
+    let fd = resolver
+        .safe_openat(dirfd.as_raw_fd(), "sub/../file", libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(read_all(&mut fd.into()), "content");
+}
+
+#[test]
+fn test_beneath_and_in_root() {
+    let dir = scratch_dir("beneath");
+    fs::create_dir_all(dir.join("root/etc")).unwrap();
+    fs::write(dir.join("root/etc/passwd"), "inside").unwrap();
+    fs::write(dir.join("passwd"), "outside").unwrap();
+    unix_fs::symlink("/etc/passwd", dir.join("root/absolute")).unwrap();
+    unix_fs::symlink("../../passwd", dir.join("root/etc/up")).unwrap();
+    let root = fs::File::open(dir.join("root")).unwrap();
+
+    for path in ["/etc/passwd", "absolute", "etc/up", ".."] {
+        let res = resolver().safe_open_beneath(root.as_raw_fd(), path, libc::O_RDONLY);
+        assert!(
+            matches!(res, Err(OpenError::EscapesRoot { .. })),
+            "{}: {:?}",
+            path,
+            res
+        );
+    }
+    let fd = resolver()
+        .safe_open_beneath(root.as_raw_fd(), "etc/../etc/passwd", libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(read_all(&mut fd.into()), "inside");
+
+    for path in ["/etc/passwd", "absolute", "../../etc/passwd"] {
+        let fd = resolver()
+            .safe_open_in_root(root.as_raw_fd(), path, libc::O_RDONLY)
+            .unwrap();
+        assert_eq!(read_all(&mut fd.into()), "inside", "{}", path);
+    }
+    // etc/up is ../../passwd from etc, which stops at the root
+    let res = resolver().safe_open_in_root(root.as_raw_fd(), "etc/up", libc::O_RDONLY);
+    assert!(matches!(res, Err(OpenError::NotFound { .. })));
+}
+
+#[test]
+fn test_openat2_matches_walk() {
+    let dir = scratch_dir("openat2");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/file"), "content").unwrap();
+    unix_fs::symlink("sub", dir.join("link")).unwrap();
+    let dir = dir.to_str().unwrap();
+
+    // the fast path must not judge `link/file` by its lexical path
+    let policy = || PolicySet::new().with(Glob::deny(&format!("{}/link/*", dir)));
+    for resolver in [
+        Resolver::new(policy()),
+        Resolver::new(policy()).use_openat2(false),
+    ] {
+        for path in ["sub/file", "link/file", "sub/", "."] {
+            assert!(resolver
+                .safe_open(&format!("{}/{}", dir, path), libc::O_RDONLY)
+                .is_ok());
+        }
+    }
+
+    let policy = || PolicySet::new().with(Glob::deny(&format!("{}/sub/*", dir)));
+    for resolver in [
+        Resolver::new(policy()),
+        Resolver::new(policy()).use_openat2(false),
+    ] {
+        for path in ["sub/file", "link/file"] {
+            let res = resolver.safe_open(&format!("{}/{}", dir, path), libc::O_RDONLY);
+            assert!(
+                matches!(res, Err(OpenError::AccessDenied { .. })),
+                "{}",
+                path
+            );
+        }
+        let res = resolver.safe_open(&format!("{}/sub/missing/", dir), libc::O_RDONLY);
+        assert!(matches!(res, Err(OpenError::AccessDenied { .. })));
+        let res = resolver.safe_open(&format!("{}/missing", dir), libc::O_RDONLY);
+        assert!(matches!(res, Err(OpenError::NotFound { .. })));
+    }
+}
+
+#[test]
+fn test_protected_hard_link() {
+    let dir = scratch_dir("hardlink");
+    fs::write(dir.join("secret"), "secret").unwrap();
+    fs::hard_link(dir.join("secret"), dir.join("alias")).unwrap();
+    fs::write(dir.join("other"), "other").unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    let file_policy: FilePolicy = format!("[[rule]]\ndeny = \"{}\"\n", path("secret"))
+        .parse()
+        .unwrap();
+    file_policy.refresh(&FileId::of_path);
+    let policies: [Arc<dyn Policy>; 2] = [
+        Arc::new(file_policy),
+        Arc::new(ProtectedFiles::new([path("secret")])),
+    ];
+    for policy in policies {
+        for resolver in [
+            Resolver::new(policy.clone()),
+            Resolver::new(policy.clone()).use_openat2(false),
+        ] {
+            for flags in [libc::O_RDONLY, libc::O_WRONLY | libc::O_TRUNC] {
+                let res = resolver.safe_open(&path("alias"), flags);
+                match res {
+                    Err(OpenError::AccessDenied { path: denied, rule }) => {
+                        assert_eq!(denied, path("alias"));
+                        assert!(rule.is_some());
+                    }
+                    res => panic!("unexpected {:?}", res),
+                }
+            }
+            // refused before it was truncated
+            assert_eq!(fs::read_to_string(path("secret")).unwrap(), "secret");
+
+            let fd = resolver
+                .safe_open(&path("other"), libc::O_WRONLY | libc::O_TRUNC)
+                .unwrap();
+            drop(fd);
+            assert_eq!(fs::read_to_string(path("other")).unwrap(), "");
+            fs::write(path("other"), "other").unwrap();
+        }
+    }
+
+    // a replaced file is protected once the policy is refreshed
+    let resolver = Resolver::new(ProtectedFiles::new([path("secret")]));
+    fs::remove_file(path("secret")).unwrap();
+    fs::hard_link(path("other"), path("secret")).unwrap();
+    assert!(resolver.safe_open(&path("alias"), libc::O_RDONLY).is_err());
+    assert!(resolver.safe_open(&path("other"), libc::O_RDONLY).is_ok());
+    resolver.refresh_policy();
+    assert!(resolver.safe_open(&path("alias"), libc::O_RDONLY).is_ok());
+    let res = resolver.safe_open(&path("other"), libc::O_RDONLY);
+    assert!(matches!(res, Err(OpenError::AccessDenied { .. })));
+}
+
+/// Resolves the links out of the jail at `root` on either backend.
+fn check_jail<F: FileSystem>(resolver: &Resolver<F>, root: RawFd) {
+    for path in ["absolute", "etc/up"] {
+        let res = resolver.safe_open_beneath(root, path, libc::O_RDONLY);
+        assert!(
+            matches!(res, Err(OpenError::EscapesRoot { .. })),
+            "{}",
+            path
+        );
+        let fd = resolver
+            .safe_open_in_root(root, path, libc::O_RDONLY)
+            .unwrap();
+        let mut content = [0u8; 16];
+        let length = fd.fs().read(fd.as_raw_fd(), &mut content).unwrap();
+        assert_eq!(&content[..length], b"inside", "{}", path);
+    }
+    let alias = resolver.safe_openat(root, "alias", libc::O_RDONLY).unwrap();
+    let passwd = resolver
+        .safe_openat(root, "etc/passwd", libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(alias.file_id().unwrap(), passwd.file_id().unwrap());
+}
+
+/// Opens the hard link to the jail's passwd on either backend, under policies
+/// that protect the file by its path there.
+fn check_protected<F: FileSystem>(fs: &F, jail: &str, root: RawFd) {
+    let secret = format!("{}/etc/passwd", jail);
+    let file_policy: FilePolicy = format!("[[rule]]\ndeny = \"{}\"\n", secret)
+        .parse()
+        .unwrap();
+    let policies: [Arc<dyn Policy>; 2] = [
+        Arc::new(file_policy),
+        Arc::new(ProtectedFiles::new([secret.clone()])),
+    ];
+    for policy in policies {
+        let resolver = Resolver::with_fs(fs.clone(), policy);
+        resolver.refresh_policy();
+        let res = resolver.safe_openat(root, "alias", libc::O_RDONLY);
+        assert!(
+            matches!(res, Err(OpenError::AccessDenied { rule: Some(_), .. })),
+            "{}: {:?}",
+            secret,
+            res
+        );
+    }
+}
+
+/// One scenario, built as a fixture, resolved in the mock and then by the
+/// kernel once written out.
+#[test]
+fn test_both_backends() {
+    let mock = Fixture::new()
+        .file("/jail/etc/passwd", "inside")
+        .mode(0o600)
+        .symlink("/jail/absolute", "/etc/passwd")
+        .symlink("/jail/etc/up", "../../etc/passwd")
+        .hard_link("/jail/alias", "/jail/etc/passwd")
+        .build()
+        .unwrap();
+    let resolver = Resolver::with_fs(mock.clone(), PolicySet::new());
+    let root = mock
+        .openat(libc::AT_FDCWD, c"/jail", libc::O_RDONLY, 0)
+        .unwrap();
+    check_jail(&resolver, root);
+    check_protected(&mock, "/jail", root);
+    mock.close(root).unwrap();
+
+    let dir = scratch_dir("exported");
+    mock.export("/jail", &dir).unwrap();
+    assert_eq!(
+        fs::metadata(dir.join("etc/passwd"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777,
+        0o600
+    );
+    let root = fs::File::open(&dir).unwrap();
+    check_jail(
+        &Resolver::with_fs(LibcFs, PolicySet::new()),
+        root.as_raw_fd(),
+    );
+    check_protected(&LibcFs, dir.to_str().unwrap(), root.as_raw_fd());
+
+    // building a resolver leaves a shared policy as it is
+    let exported = format!("{}/etc/passwd", dir.display());
+    let protected = Arc::new(ProtectedFiles::new([exported]));
+    let libc = Resolver::with_fs(LibcFs, protected.clone());
+    let _mock = Resolver::with_fs(mock.clone(), protected);
+    let res = libc.safe_openat(root.as_raw_fd(), "alias", libc::O_RDONLY);
+    assert!(matches!(res, Err(OpenError::AccessDenied { .. })));
+}
+
+#[test]
+fn test_created_file_mode() {
+    let dir = scratch_dir("created");
+    // the umask is per process, and 022 is what the other tests expect too
+    unsafe { libc::umask(0o022) };
+    for (resolver, name) in [
+        (resolver(), "fast"),
+        (resolver().use_openat2(false), "walk"),
+    ] {
+        let path = dir.join(name);
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+        resolver.safe_open(path.to_str().unwrap(), flags).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o644, "{}", name);
+    }
+}
+
+/// Opens the links in `root` as the flags say a final symlink is opened, on
+/// either backend and with or without `openat2`.
+fn check_final_symlink<F: FileSystem>(fs: &F, root: RawFd) {
+    for fast in [true, false] {
+        let resolver = Resolver::with_fs(fs.clone(), PolicySet::new()).use_openat2(fast);
+        let res = resolver.safe_openat(root, "link", libc::O_RDONLY | libc::O_NOFOLLOW);
+        assert_eq!(res.unwrap_err().errno(), libc::ELOOP, "{}", fast);
+
+        let fd = resolver
+            .safe_openat(root, "link", libc::O_PATH | libc::O_NOFOLLOW)
+            .unwrap();
+        let st = fs.fstat(fd.as_raw_fd()).unwrap();
+        assert_eq!(st.st_mode & libc::S_IFMT, libc::S_IFLNK, "{}", fast);
+
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+        let res = resolver.safe_openat(root, "dangling", flags);
+        assert_eq!(res.unwrap_err().errno(), libc::EEXIST, "{}", fast);
+        let res = resolver.safe_openat(root, "missing", libc::O_RDONLY);
+        assert!(matches!(res, Err(OpenError::NotFound { .. })), "{}", fast);
+    }
+}
+
+#[test]
+fn test_final_symlink_flags() {
+    let mock = Fixture::new()
+        .file("/links/file", "content")
+        .symlink("/links/link", "file")
+        .symlink("/links/dangling", "missing")
+        .build()
+        .unwrap();
+    let root = mock
+        .openat(libc::AT_FDCWD, c"/links", libc::O_RDONLY, 0)
+        .unwrap();
+    check_final_symlink(&mock, root);
+    mock.close(root).unwrap();
+
+    let dir = scratch_dir("final-links");
+    mock.export("/links", &dir).unwrap();
+    let root = fs::File::open(&dir).unwrap();
+    check_final_symlink(&LibcFs, root.as_raw_fd());
+}
+
+#[test]
+fn test_fifo_component() {
+    let dir = scratch_dir("fifo");
+    let fifo = dir.join("fifo");
+    let path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o644) }, 0);
+
+    // walked through as a directory, the FIFO is not opened for reading,
+    // which would block until a writer shows up
+    for resolver in [resolver(), resolver().use_openat2(false)] {
+        let res = resolver.safe_open(fifo.join("file").to_str().unwrap(), libc::O_RDONLY);
+        assert!(
+            matches!(res, Err(OpenError::NotADirectory { .. })),
+            "{:?}",
+            res
+        );
+    }
+}